@@ -0,0 +1,403 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+// Arbitrary-precision integer stored as a sign and a magnitude of base 2^32
+// digits, least significant first. The magnitude never has leading zero
+// digits, and zero is always positive, so equal numbers have equal
+// representations and the derived `Eq` and `Hash` can be used as is.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    digits: Vec<u32>,
+}
+
+impl BigInt {
+    pub fn zero() -> Self {
+        BigInt {
+            negative: false,
+            digits: Vec::new(),
+        }
+    }
+
+    pub fn from_i64(int: i64) -> Self {
+        let magnitude = int.unsigned_abs();
+        BigInt::new(int < 0, vec![magnitude as u32, (magnitude >> 32) as u32])
+    }
+
+    // Truncates toward zero. Infinities and NaN have no integer value.
+    pub fn from_f64(number: f64) -> Option<Self> {
+        if !number.is_finite() {
+            return None;
+        }
+        let magnitude = number.abs().trunc();
+        if magnitude < 1.0 {
+            return Some(BigInt::zero());
+        }
+
+        // The float is `mantissa * 2^exponent` with a 53 bit mantissa.
+        let bits = magnitude.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as i64 - 1075;
+        let mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+        let mantissa = BigInt::from_i64(mantissa as i64);
+        let result = if exponent >= 0 {
+            mantissa.shift_left(exponent as usize)
+        } else {
+            BigInt::from_i64((mantissa.to_i64()?) >> -exponent)
+        };
+        Some(if number < 0.0 { result.neg() } else { result })
+    }
+
+    // Accepts an optional sign followed by digits in the given radix, with
+    // `_` allowed between digits.
+    pub fn parse(text: &str, radix: u32) -> Option<Self> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        if digits.is_empty() || digits.starts_with('_') || digits.ends_with('_') {
+            return None;
+        }
+
+        let mut result = BigInt::zero();
+        for c in digits.chars().filter(|c| *c != '_') {
+            let digit = c.to_digit(radix)?;
+            result = result.mul_small(radix).add_small(digit);
+        }
+        Some(if negative { result.neg() } else { result })
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.digits.len() > 2 {
+            return None;
+        }
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0u64, |acc, digit| (acc << 32) | *digit as u64);
+        if self.negative {
+            0i64.checked_sub_unsigned(magnitude)
+        } else {
+            i64::try_from(magnitude).ok()
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self
+            .digits
+            .iter()
+            .rev()
+            .fold(0.0, |acc, digit| acc * 4294967296.0 + *digit as f64);
+        if self.negative {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+
+    // Bytes owned by the digits.
+    pub fn size(&self) -> usize {
+        self.digits.capacity() * std::mem::size_of::<u32>()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn neg(&self) -> Self {
+        BigInt::new(!self.negative, self.digits.clone())
+    }
+
+    pub fn add(&self, other: &BigInt) -> Self {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_magnitudes(&self.digits, &other.digits));
+        }
+        match compare_magnitudes(&self.digits, &other.digits) {
+            Ordering::Less => {
+                BigInt::new(other.negative, sub_magnitudes(&other.digits, &self.digits))
+            }
+            _ => BigInt::new(self.negative, sub_magnitudes(&self.digits, &other.digits)),
+        }
+    }
+
+    pub fn sub(&self, other: &BigInt) -> Self {
+        self.add(&other.neg())
+    }
+
+    pub fn mul(&self, other: &BigInt) -> Self {
+        let mut digits = vec![0u32; self.digits.len() + other.digits.len()];
+        for (i, a) in self.digits.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, b) in other.digits.iter().enumerate() {
+                let product = *a as u64 * *b as u64 + digits[i + j] as u64 + carry;
+                digits[i + j] = product as u32;
+                carry = product >> 32;
+            }
+            digits[i + other.digits.len()] = carry as u32;
+        }
+        BigInt::new(self.negative != other.negative, digits)
+    }
+
+    // Floored division: the remainder takes the sign of the divisor, like
+    // `//` and `%` on small ints. Returns `None` when dividing by zero.
+    pub fn div_rem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (quotient, remainder) = divide_magnitudes(&self.digits, &other.digits);
+        let quotient = BigInt::new(self.negative != other.negative, quotient);
+        let remainder = BigInt::new(self.negative, remainder);
+        if !remainder.is_zero() && remainder.negative != other.negative {
+            Some((quotient.sub(&BigInt::from_i64(1)), remainder.add(other)))
+        } else {
+            Some((quotient, remainder))
+        }
+    }
+
+    pub fn pow(&self, mut exponent: u32) -> Self {
+        let mut result = BigInt::from_i64(1);
+        let mut base = self.clone();
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = result.mul(&base);
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = base.mul(&base);
+            }
+        }
+        result
+    }
+
+    // `!x` is `-x - 1` in two's complement.
+    pub fn not(&self) -> Self {
+        self.neg().sub(&BigInt::from_i64(1))
+    }
+
+    // The bitwise operators act as if both numbers were in two's complement
+    // with infinitely many sign bits, like on small ints.
+    pub fn and(&self, other: &BigInt) -> Self {
+        self.bitwise(other, |a, b| a & b)
+    }
+
+    pub fn or(&self, other: &BigInt) -> Self {
+        self.bitwise(other, |a, b| a | b)
+    }
+
+    pub fn xor(&self, other: &BigInt) -> Self {
+        self.bitwise(other, |a, b| a ^ b)
+    }
+
+    pub fn shift_left(&self, bits: usize) -> Self {
+        let mut digits = vec![0u32; bits / 32];
+        let shift = bits % 32;
+        let mut carry = 0u32;
+        for digit in &self.digits {
+            if shift == 0 {
+                digits.push(*digit);
+            } else {
+                digits.push((digit << shift) | carry);
+                carry = digit >> (32 - shift);
+            }
+        }
+        digits.push(carry);
+        BigInt::new(self.negative, digits)
+    }
+
+    // Rounds toward negative infinity like `>>` on small ints.
+    pub fn shift_right(&self, bits: usize) -> Self {
+        let skip = bits / 32;
+        let shift = bits % 32;
+        let kept = self.digits.get(skip..).unwrap_or_default();
+        let digits = (0..kept.len())
+            .map(|i| {
+                let high = kept.get(i + 1).copied().unwrap_or(0) as u64;
+                (((high << 32) | kept[i] as u64) >> shift) as u32
+            })
+            .collect();
+        let result = BigInt::new(self.negative, digits);
+
+        let lost = self.digits.iter().take(skip).any(|digit| *digit != 0)
+            || kept
+                .first()
+                .is_some_and(|digit| digit & ((1u32 << shift) - 1) != 0);
+        if self.negative && lost {
+            result.sub(&BigInt::from_i64(1))
+        } else {
+            result
+        }
+    }
+
+    // Digits in the given radix with a leading `-` for negative numbers and
+    // no prefix.
+    pub fn to_string_radix(&self, radix: u32) -> String {
+        if self.is_zero() {
+            return "0".to_string();
+        }
+        let mut digits = Vec::new();
+        let mut magnitude = self.digits.clone();
+        while !magnitude.is_empty() {
+            let remainder = div_small_in_place(&mut magnitude, radix);
+            digits.push(std::char::from_digit(remainder, radix).unwrap());
+        }
+        if self.negative {
+            digits.push('-');
+        }
+        digits.iter().rev().collect()
+    }
+
+    fn new(negative: bool, mut digits: Vec<u32>) -> Self {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        let negative = negative && !digits.is_empty();
+        BigInt { negative, digits }
+    }
+
+    fn mul_small(&self, factor: u32) -> Self {
+        self.mul(&BigInt::from_i64(factor as i64))
+    }
+
+    fn add_small(&self, addend: u32) -> Self {
+        self.add(&BigInt::from_i64(addend as i64))
+    }
+
+    fn bitwise(&self, other: &BigInt, op: impl Fn(u32, u32) -> u32) -> Self {
+        // One extra digit leaves room for the sign bit.
+        let len = self.digits.len().max(other.digits.len()) + 1;
+        let (a, b) = (self.twos_complement(len), other.twos_complement(len));
+        let mut digits: Vec<u32> = a.iter().zip(&b).map(|(a, b)| op(*a, *b)).collect();
+        let negative = digits.last().is_some_and(|digit| digit >> 31 == 1);
+        if negative {
+            negate_in_place(&mut digits);
+        }
+        BigInt::new(negative, digits)
+    }
+
+    // The digits of the number in two's complement, sign-extended to `len`.
+    fn twos_complement(&self, len: usize) -> Vec<u32> {
+        let mut digits = self.digits.clone();
+        digits.resize(len, 0);
+        if self.negative {
+            negate_in_place(&mut digits);
+        }
+        digits
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitudes(&self.digits, &other.digits),
+            (true, true) => compare_magnitudes(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_string_radix(10))
+    }
+}
+
+fn compare_magnitudes(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut digits = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len().max(b.len()) {
+        let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        digits.push(sum as u32);
+        carry = sum >> 32;
+    }
+    digits.push(carry as u32);
+    digits
+}
+
+// Expects `a >= b`.
+fn sub_magnitudes(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut digits = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, digit) in a.iter().enumerate() {
+        let mut difference = *digit as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if difference < 0 {
+            difference += 1 << 32;
+            borrow = 1;
+        }
+        digits.push(difference as u32);
+    }
+    digits
+}
+
+// Two's complement negation: inverts the digits and adds one.
+fn negate_in_place(digits: &mut [u32]) {
+    let mut carry = true;
+    for digit in digits.iter_mut() {
+        let (sum, overflow) = (!*digit).overflowing_add(carry as u32);
+        *digit = sum;
+        carry = overflow;
+    }
+}
+
+// Divides in place and returns the remainder.
+fn div_small_in_place(digits: &mut Vec<u32>, divisor: u32) -> u32 {
+    let mut remainder = 0u64;
+    for digit in digits.iter_mut().rev() {
+        let current = (remainder << 32) | *digit as u64;
+        *digit = (current / divisor as u64) as u32;
+        remainder = current % divisor as u64;
+    }
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    remainder as u32
+}
+
+// Truncating division by shifting in one bit of the dividend at a time,
+// which is plenty fast for the sizes scripts work with.
+fn divide_magnitudes(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if compare_magnitudes(a, b) == Ordering::Less {
+        return (Vec::new(), a.to_vec());
+    }
+    if b.len() == 1 {
+        let mut quotient = a.to_vec();
+        let remainder = div_small_in_place(&mut quotient, b[0]);
+        return (quotient, vec![remainder]);
+    }
+
+    let mut quotient = vec![0u32; a.len()];
+    let mut remainder: Vec<u32> = Vec::new();
+    for i in (0..a.len() * 32).rev() {
+        let bit = (a[i / 32] >> (i % 32)) & 1;
+        remainder = BigInt::new(false, remainder).shift_left(1).digits;
+        if bit == 1 {
+            if remainder.is_empty() {
+                remainder.push(1);
+            } else {
+                remainder[0] |= 1;
+            }
+        }
+        if compare_magnitudes(&remainder, b) != Ordering::Less {
+            remainder = BigInt::new(false, sub_magnitudes(&remainder, b)).digits;
+            quotient[i / 32] |= 1 << (i % 32);
+        }
+    }
+    (quotient, remainder)
+}
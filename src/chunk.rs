@@ -1,3 +1,5 @@
+use crate::memory::ObjRef;
+
 #[derive(Debug)]
 pub struct Chunk {
     pub code: Vec<OpCode>,
@@ -12,12 +14,20 @@ struct LineEntry {
 }
 
 // maybe define methods/trait on the enum
-#[derive(Debug, Clone)]
+// Strings are interned, so comparing handles compares their contents.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Value {
+    Int(i64),
     Number(f64),
-    String(String),
     Boolean(bool),
     Nil,
+    Obj(ObjRef),
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
@@ -29,7 +39,15 @@ impl Chunk {
         }
     }
 
-    pub fn write(&mut self, opcode: OpCode) {
+    pub fn write(&mut self, opcode: OpCode, line: usize) {
+        match self.lines.last_mut() {
+            Some(entry) if entry.line == line => entry.run_length += 1,
+            _ => self.lines.push(LineEntry {
+                line,
+                run_length: 1,
+            }),
+        }
+
         //self.code.push(opcode);
         match opcode {
             OpCode::Constant(index) => {
@@ -41,32 +59,74 @@ impl Chunk {
         }
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
-        //println!("Adding constant: {:?}", value);
+    // Takes back the last instruction written, so the compiler can replace
+    // a read with an update once it sees what the read was used for.
+    pub fn pop(&mut self) -> Option<OpCode> {
+        let opcode = self.code.pop()?;
+        if let Some(entry) = self.lines.last_mut() {
+            entry.run_length -= 1;
+            if entry.run_length == 0 {
+                self.lines.pop();
+            }
+        }
+        Some(opcode)
+    }
+
+    pub fn get_line(&self, offset: usize) -> usize {
+        let mut remaining = offset;
+        for entry in &self.lines {
+            if remaining < entry.run_length {
+                return entry.line;
+            }
+            remaining -= entry.run_length;
+        }
+        0
+    }
+
+    // Every use of the same literal or name shares one constant, strings
+    // being interned. Floats are told apart by their bits, so `-0.0` keeps
+    // its own constant.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        let same = |constant: &Value| match (constant, &value) {
+            (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+            _ => *constant == value,
+        };
+        if let Some(index) = self.constants.iter().position(same) {
+            return index;
+        }
         self.constants.push(value);
-        (self.constants.len() - 1) as u8
+        self.constants.len() - 1
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum OpCode {
     Constant(u8),
     Negate,
-    ConstantLong,
+    // Loads constants past the 256 that `Constant` can address.
+    ConstantLong(u16),
     Print,
-    Jump,
-    JumpIfFalse,
-    Loop,
+    // Jump offsets count instructions from the one after the jump.
+    Jump(u16),
+    JumpIfFalse(u16),
+    // Jumps without popping when the value on top of the stack isn't nil.
+    JumpIfNotNil(u16),
+    Loop(u16),
     Return,
     Nil,
     True,
     False,
     Pop,
+    // Pushes a copy of the value that many slots below the top.
+    Dup(u8),
+    // Moves the value on top of the stack below that many values, closing
+    // any upvalues over the slots it shifts.
+    Bury(u8),
     GetLocal(u8),
     SetLocal(u8),
-    GetGlobal(u8),
-    SetGlobal(u8),
-    DefineGlobal(u8),
+    GetGlobal(u16),
+    SetGlobal(u16),
+    DefineGlobal(u16),
     Equal,
     Greater,
     Less,
@@ -74,5 +134,72 @@ pub enum OpCode {
     Subtract,
     Multiply,
     Divide,
+    // Floored division and the matching remainder, as in Python.
+    IntDivide,
+    Modulo,
+    Power,
+    // Bitwise operators, defined for numbers that are exact integers.
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
     Not,
+    ToString,
+    BuildList(u8),
+    // Builds a map from that many key/value pairs.
+    BuildMap(u8),
+    GetIndex,
+    SetIndex,
+    // Calls with the given number of arguments, the last of which are named
+    // by the list of strings in the constant.
+    CallNamed(u8, u8),
+    // Calls with the arguments taken from that many lists on the stack.
+    CallSpread(u8),
+    // Jumps forward by the offset unless the argument for the parameter with
+    // that index was left out of the call, in which case its default value
+    // is computed next.
+    JumpIfSupplied(u8, u16),
+    // Replaces a list of exactly that many elements with its elements.
+    UnpackList(u8),
+    // Replaces the value on top of the stack with whether it is a list of
+    // exactly that many elements.
+    MatchList(u8),
+    // Calls the method named by the constant with the given argument count.
+    Invoke(u8, u8),
+    Range,
+    // Replaces the value on top of the stack with an iterator over it.
+    GetIter,
+    // Pushes the next value of the iterator in the given local slot, or jumps
+    // forward by the offset once it is exhausted.
+    ForIter(u8, u16),
+    GetProperty(u8),
+    SetProperty(u8),
+    // Pushes a new class named by the constant.
+    Class(u8),
+    // Add the closure on top of the stack to the class below it under the
+    // name in the constant, and pop the closure.
+    Method(u8),
+    StaticMethod(u8),
+    Getter(u8),
+    Setter(u8),
+    Throw,
+    // Installs an exception handler that unwinds the stack to its current
+    // height and continues at the given forward offset.
+    PushHandler(u16),
+    PopHandler,
+    // Ends a `finally` block: pops the pending exception and the flag that
+    // says whether it has to be thrown again.
+    EndFinally,
+    // Pushes the module at the path in the given constant, running it first
+    // unless an earlier import already did.
+    Import(u8),
+    Call(u8),
+    // Wraps the function in the given constant in a closure.
+    Closure(u8),
+    GetUpvalue(u8),
+    SetUpvalue(u8),
+    // Moves the local on top of the stack into its upvalue and pops it.
+    CloseUpvalue,
 }
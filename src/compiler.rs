@@ -1,13 +1,20 @@
 use crate::{
+    bigint::BigInt,
     chunk::{Chunk, OpCode, Value},
-    scanner::Scanner,
+    globals::Globals,
+    memory::{Heap, ObjRef},
+    object::{FunctionKind, LoxFunction, Obj, UpvalueSource},
+    scanner::{self, Scanner},
     token::{Token, TokenType},
 };
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::mem;
 use std::sync::LazyLock;
-use std::{cell::RefCell, process};
 
-type ParseFn = fn(&Parser);
+// The flag tells whether the expression may be the target of an
+// assignment, which only holds when parsing at assignment precedence.
+type ParseFn = fn(&Parser, bool);
 
 #[derive(Debug, Clone)]
 pub struct ParseRule {
@@ -16,36 +23,174 @@ pub struct ParseRule {
     precedence: Precedence,
 }
 
-// struct Local<'a> {
-//     token: Token<'a>,
-// }
+struct Loop {
+    // Where `continue` jumps back to.
+    start: usize,
+    // Locals deeper than this belong to the loop body and are popped before
+    // jumping out of it.
+    scope_depth: u8,
+    // Exception handlers installed inside the loop body are removed before
+    // jumping out of it.
+    handler_count: usize,
+    break_jumps: Vec<usize>,
+}
+
+// An exception handler installed by a `try` statement.
+struct Handler {
+    is_finally: bool,
+    // Locals declared outside the `try` statement.
+    local_count: usize,
+    // The `return`, `break` and `continue` statements that jump to the
+    // `finally` block on their way out, with the offsets of their jumps.
+    exits: Vec<(Exit, usize)>,
+}
+
+// A statement that leaves the code it is in. Its discriminant tells the
+// `finally` blocks it passes through where to continue.
+#[derive(Clone, Copy, PartialEq)]
+enum Exit {
+    Return,
+    Break,
+    Continue,
+}
+
+struct Local<'a> {
+    token: Token<'a>,
+    // `None` while the variable's own initializer is being compiled.
+    depth: Option<u8>,
+    // Captured locals are moved into their upvalue instead of being popped
+    // when their scope ends.
+    is_captured: bool,
+    is_const: bool,
+}
+
+// The state of an enclosing function, put aside while a function nested in
+// it is compiled.
+struct FunctionState<'a> {
+    chunk: Chunk,
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<UpvalueSource>,
+    scope_depth: u8,
+    loops: Vec<Loop>,
+    handlers: Vec<Handler>,
+    kind: FunctionKind,
+}
+
+// Something an assignment can store into, known by the instruction that
+// reads it.
+#[derive(Clone, Copy)]
+enum Target {
+    Local(u8),
+    Upvalue(u8),
+    Global(u16),
+    Property(u8),
+    Index,
+}
+
+impl Target {
+    fn get(self) -> OpCode {
+        match self {
+            Target::Local(slot) => OpCode::GetLocal(slot),
+            Target::Upvalue(index) => OpCode::GetUpvalue(index),
+            Target::Global(slot) => OpCode::GetGlobal(slot),
+            Target::Property(name) => OpCode::GetProperty(name),
+            Target::Index => OpCode::GetIndex,
+        }
+    }
+
+    fn set(self) -> OpCode {
+        match self {
+            Target::Local(slot) => OpCode::SetLocal(slot),
+            Target::Upvalue(index) => OpCode::SetUpvalue(index),
+            Target::Global(slot) => OpCode::SetGlobal(slot),
+            Target::Property(name) => OpCode::SetProperty(name),
+            Target::Index => OpCode::SetIndex,
+        }
+    }
+
+    // How many values, the object and the index, the target expects on the
+    // stack below the value being stored.
+    fn operands(self) -> u8 {
+        match self {
+            Target::Property(_) => 1,
+            Target::Index => 2,
+            _ => 0,
+        }
+    }
+}
+
+// What a compiled `match` pattern leaves for its arm: the jumps taken when
+// the subject does not match, and the variables to bind when it does, each
+// with the list indices leading from the subject to its value.
+#[derive(Default)]
+struct Pattern<'a> {
+    fail_jumps: Vec<usize>,
+    bindings: Vec<(Token<'a>, Vec<u8>)>,
+}
+
+// Which subjects a pattern accepts, as far as finding unreachable arms goes.
+// Literals are kept as their source text.
+enum Coverage {
+    All,
+    Literals(Vec<String>),
+    Partial,
+}
+
+impl Coverage {
+    fn or(self, other: Coverage) -> Coverage {
+        match (self, other) {
+            (Coverage::All, _) | (_, Coverage::All) => Coverage::All,
+            (Coverage::Literals(mut a), Coverage::Literals(b)) => {
+                a.extend(b);
+                Coverage::Literals(a)
+            }
+            _ => Coverage::Partial,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub enum Precedence {
-    None,       // No precedence
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
-    Primary,    // Primary expressions
+    None,        // No precedence
+    Assignment,  // =
+    Conditional, // ?:
+    Coalesce,    // ??
+    Or,          // or
+    And,         // and
+    BitOr,       // |
+    BitXor,      // ^
+    BitAnd,      // &
+    Equality,    // == !=
+    Comparison,  // < > <= >=
+    Range,       // ..
+    Shift,       // << >>
+    Term,        // + -
+    Factor,      // * / % //
+    Unary,       // ! -
+    Exponent,    // **
+    Call,        // . ()
+    Primary,     // Primary expressions
 }
 impl Precedence {
     pub fn next(&self) -> Option<Self> {
         match self {
             Precedence::None => Some(Precedence::Assignment),
-            Precedence::Assignment => Some(Precedence::Or),
+            Precedence::Assignment => Some(Precedence::Conditional),
+            Precedence::Conditional => Some(Precedence::Coalesce),
+            Precedence::Coalesce => Some(Precedence::Or),
             Precedence::Or => Some(Precedence::And),
-            Precedence::And => Some(Precedence::Equality),
+            Precedence::And => Some(Precedence::BitOr),
+            Precedence::BitOr => Some(Precedence::BitXor),
+            Precedence::BitXor => Some(Precedence::BitAnd),
+            Precedence::BitAnd => Some(Precedence::Equality),
             Precedence::Equality => Some(Precedence::Comparison),
-            Precedence::Comparison => Some(Precedence::Term),
+            Precedence::Comparison => Some(Precedence::Range),
+            Precedence::Range => Some(Precedence::Shift),
+            Precedence::Shift => Some(Precedence::Term),
             Precedence::Term => Some(Precedence::Factor),
             Precedence::Factor => Some(Precedence::Unary),
-            Precedence::Unary => Some(Precedence::Call),
+            Precedence::Unary => Some(Precedence::Exponent),
+            Precedence::Exponent => Some(Precedence::Call),
             Precedence::Call => Some(Precedence::Primary),
             Precedence::Primary => None, // No next precedence
         }
@@ -55,127 +200,1360 @@ impl Precedence {
 struct Parser<'a> {
     scanner: Scanner<'a>,
     chunk: RefCell<&'a mut Chunk>,
+    heap: RefCell<&'a mut Heap>,
+    globals: RefCell<&'a mut Globals>,
+    vm_roots: &'a [Value],
     current: RefCell<Option<Token<'a>>>,
     previous: RefCell<Option<Token<'a>>>,
     had_error: RefCell<bool>,
     panic_mode: RefCell<bool>,
-    locals: RefCell<Vec<Token<'a>>>,
+    locals: RefCell<Vec<Local<'a>>>,
     scope_depth: RefCell<u8>,
-    local_count: RefCell<u8>
+    loops: RefCell<Vec<Loop>>,
+    // One entry per exception handler active at the current point of the
+    // code, innermost last.
+    handlers: RefCell<Vec<Handler>>,
+    upvalues: RefCell<Vec<UpvalueSource>>,
+    // Outermost first, starting with the top level of the module.
+    enclosing: RefCell<Vec<FunctionState<'a>>>,
+    function_kind: RefCell<FunctionKind>,
+    // How many class bodies the code is nested in, which is where `this`
+    // can be used.
+    class_depth: RefCell<usize>,
+    // The target read by the last instruction emitted, if it was one. Lets
+    // `++x` and `--x` turn the read into an update.
+    target: RefCell<Option<Target>>,
 }
 
 impl<'a> Parser<'a> {
-    fn new(chunk: &'a mut Chunk, source: &'a str) -> Self {
+    fn new(
+        chunk: &'a mut Chunk,
+        source: &'a str,
+        heap: &'a mut Heap,
+        globals: &'a mut Globals,
+        vm_roots: &'a [Value],
+    ) -> Self {
         Self {
             chunk: RefCell::new(chunk),
+            heap: RefCell::new(heap),
+            globals: RefCell::new(globals),
+            vm_roots,
             scanner: Scanner::new(source),
             current: RefCell::new(None),
             previous: RefCell::new(None),
             had_error: RefCell::new(false),
             panic_mode: RefCell::new(false),
-            locals: RefCell::new(Vec::new()),
+            locals: RefCell::new(vec![Self::callee_slot()]),
             scope_depth: RefCell::new(0),
-            local_count: RefCell::new(0),
+            loops: RefCell::new(Vec::new()),
+            handlers: RefCell::new(Vec::new()),
+            upvalues: RefCell::new(Vec::new()),
+            enclosing: RefCell::new(Vec::new()),
+            function_kind: RefCell::new(FunctionKind::Script),
+            class_depth: RefCell::new(0),
+            target: RefCell::new(None),
+        }
+    }
+
+    // Slot zero of every function holds the closure being called, or the
+    // module for a module's top level. Its empty name can't be referenced.
+    fn callee_slot() -> Local<'a> {
+        Self::slot_zero("")
+    }
+
+    // Methods find their receiver in slot zero instead.
+    fn receiver_slot() -> Local<'a> {
+        Self::slot_zero("this")
+    }
+
+    fn slot_zero(lexeme: &'a str) -> Local<'a> {
+        Local {
+            token: Token {
+                token_type: TokenType::Identifier,
+                line: 0,
+                lexeme,
+            },
+            depth: Some(0),
+            is_captured: false,
+            is_const: false,
         }
     }
 
     fn current_token(&self) -> Option<Token<'a>> {
         self.current.borrow().clone()
     }
-    fn previous_token(&self) -> Option<Token> {
+    fn previous_token(&self) -> Option<Token<'a>> {
         self.previous.borrow().clone()
     }
 
-    fn run(&'a self) {
+    fn run(&self) {
         *self.had_error.borrow_mut() = false;
         *self.panic_mode.borrow_mut() = false;
         self.advance();
 
         while !self.match_token_type(TokenType::Eof) {
-            if self.match_token_type(TokenType::Var) {
-                self.var_declaration();
+            self.declaration();
+        }
+    }
+
+    fn declaration(&self) {
+        if self.match_token_type(TokenType::Var) {
+            self.var_declaration();
+        } else if self.match_token_type(TokenType::Const) {
+            self.const_declaration();
+        } else if self.match_token_type(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token_type(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token_type(TokenType::Import) {
+            self.import_declaration();
+        } else {
+            self.statement();
+        }
+    }
+
+    fn var_declaration(&self) {
+        if self.match_token_type(TokenType::LeftBracket)
+            || self.match_token_type(TokenType::LeftBrace)
+        {
+            self.destructuring_declaration();
+            return;
+        }
+        self.consume(TokenType::Identifier, "Expect variable name.");
+
+        let token = self.previous_token().expect("Expected previous token");
+
+        if *self.scope_depth.borrow() > 0 {
+            self.add_local(token);
+            if self.match_token_type(TokenType::Equal) {
+                self.expression();
+            } else {
+                self.emit_byte(OpCode::Nil);
+            }
+            self.consume(
+                TokenType::Semicolon,
+                "Expect ';' after variable declaration.",
+            );
+            // The value is left on the stack and becomes the local's slot.
+            self.mark_initialized();
+            return;
+        }
+
+        let global_slot = self.declared_global_slot(&token);
+
+        if self.match_token_type(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::Nil);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+        self.emit_byte(OpCode::DefineGlobal(global_slot));
+    }
+
+    // Like `var`, but the initializer is required and the variable can never
+    // be assigned again.
+    fn const_declaration(&self) {
+        self.consume(TokenType::Identifier, "Expect constant name.");
+        let token = self.previous_token().expect("Expected previous token");
+
+        if *self.scope_depth.borrow() > 0 {
+            self.add_local(token);
+            self.consume(TokenType::Equal, "Expect '=' after constant name.");
+            self.expression();
+            self.consume(
+                TokenType::Semicolon,
+                "Expect ';' after constant declaration.",
+            );
+            self.mark_initialized();
+            if let Some(local) = self.locals.borrow_mut().last_mut() {
+                local.is_const = true;
+            }
+            return;
+        }
+
+        let global_slot = self.declared_global_slot(&token);
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after constant declaration.",
+        );
+        self.emit_byte(OpCode::DefineGlobal(global_slot));
+        self.globals.borrow_mut().make_const(global_slot);
+    }
+
+    // `var [a, b] = list;` binds the elements of a list of exactly that
+    // length and `var {x, y} = object;` the fields of the same names.
+    fn destructuring_declaration(&self) {
+        let (is_list, names) = self.destructuring_pattern();
+        self.consume(TokenType::Equal, "Expect '=' after destructuring pattern.");
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+        self.emit_unpack(is_list, &names);
+
+        if *self.scope_depth.borrow() > 0 {
+            for name in names {
+                self.add_local(name);
+                self.mark_initialized();
+            }
+            return;
+        }
+        // The last value is on top of the stack.
+        for name in names.iter().rev() {
+            let global_slot = self.declared_global_slot(name);
+            self.emit_byte(OpCode::DefineGlobal(global_slot));
+        }
+    }
+
+    // Parses the names of `[a, b]` or `{x, y}`, called after the opening
+    // bracket or brace. Returns whether it is a list pattern.
+    fn destructuring_pattern(&self) -> (bool, Vec<Token<'a>>) {
+        let is_list = self.previous_token().unwrap().token_type == TokenType::LeftBracket;
+        let (closing, message) = if is_list {
+            (
+                TokenType::RightBracket,
+                "Expect ']' after destructuring pattern.",
+            )
+        } else {
+            (
+                TokenType::RightBrace,
+                "Expect '}' after destructuring pattern.",
+            )
+        };
+
+        let mut names = Vec::new();
+        if !self.check(closing.clone()) {
+            loop {
+                self.consume(TokenType::Identifier, "Expect variable name.");
+                names.push(self.previous_token().unwrap());
+                if names.len() > u8::MAX as usize {
+                    self.error_at(
+                        self.previous_token(),
+                        "Can't destructure more than 255 variables.",
+                    );
+                }
+                if !self.match_token_type(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(closing, message);
+        (is_list, names)
+    }
+
+    // Replaces the value on top of the stack with the values the names are
+    // bound to, in order.
+    fn emit_unpack(&self, is_list: bool, names: &[Token]) {
+        if is_list {
+            self.emit_byte(OpCode::UnpackList(names.len() as u8));
+            return;
+        }
+        // Each field is moved below the object, which stays on top until
+        // all of them have been read.
+        for name in names {
+            let name = self.identifier_constant(name);
+            self.emit_bytes(OpCode::Dup(0), OpCode::GetProperty(name));
+            self.emit_byte(OpCode::Bury(1));
+        }
+        self.emit_byte(OpCode::Pop);
+    }
+
+    // The name is usable inside the body, so local functions can call
+    // themselves recursively.
+    fn fun_declaration(&self) {
+        self.consume(TokenType::Identifier, "Expect function name.");
+        let token = self.previous_token().expect("Expected previous token");
+
+        if *self.scope_depth.borrow() > 0 {
+            self.add_local(token.clone());
+            self.mark_initialized();
+            self.function(Some(token.lexeme), FunctionKind::Function);
+            return;
+        }
+
+        let global_slot = self.declared_global_slot(&token);
+        self.function(Some(token.lexeme), FunctionKind::Function);
+        self.emit_byte(OpCode::DefineGlobal(global_slot));
+    }
+
+    // The class is bound like a function and then pushed once more, so each
+    // member can be added to it as soon as it is compiled.
+    fn class_declaration(&self) {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let token = self.previous_token().expect("Expected previous token");
+        let name = self.identifier_constant(&token);
+
+        if *self.scope_depth.borrow() > 0 {
+            self.add_local(token.clone());
+            self.emit_byte(OpCode::Class(name));
+            self.mark_initialized();
+        } else {
+            let global_slot = self.declared_global_slot(&token);
+            self.emit_bytes(OpCode::Class(name), OpCode::DefineGlobal(global_slot));
+        }
+        let class = self.resolve_variable(&token);
+        self.emit_byte(class.get());
+
+        *self.class_depth.borrow_mut() += 1;
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.member();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        *self.class_depth.borrow_mut() -= 1;
+        self.emit_byte(OpCode::Pop);
+    }
+
+    // A method, optionally `static`, or an accessor: `get name { ... }` or
+    // `set name(value) { ... }`. `get` and `set` are only special when a
+    // name follows, so methods can still be called that.
+    fn member(&self) {
+        let is_static = self.match_token_type(TokenType::Static);
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let mut token = self.previous_token().expect("Expected previous token");
+
+        let accessor = match token.lexeme {
+            "get" => Some(FunctionKind::Getter),
+            "set" => Some(FunctionKind::Setter),
+            _ => None,
+        };
+        let kind = match accessor {
+            Some(kind) if !is_static && self.check(TokenType::Identifier) => {
+                self.advance();
+                token = self.previous_token().expect("Expected previous token");
+                kind
+            }
+            _ if !is_static && token.lexeme == "init" => FunctionKind::Initializer,
+            _ => FunctionKind::Method,
+        };
+        let name = self.identifier_constant(&token);
+
+        if kind != FunctionKind::Getter {
+            self.consume(TokenType::LeftParen, "Expect '(' after method name.");
+        }
+        self.function_after_paren(Some(token.lexeme), kind);
+        self.emit_byte(match kind {
+            _ if is_static => OpCode::StaticMethod(name),
+            FunctionKind::Getter => OpCode::Getter(name),
+            FunctionKind::Setter => OpCode::Setter(name),
+            _ => OpCode::Method(name),
+        });
+    }
+
+    // `import "path" as name;` binds the module object the way a variable
+    // declaration would. Without `as` the module is only run.
+    fn import_declaration(&self) {
+        self.consume(TokenType::String, "Expect module path after 'import'.");
+        let lexeme = self.previous_token().unwrap().lexeme;
+        let path = self.intern(scanner::string_value(lexeme));
+        let path = self.make_constant(Value::Obj(path));
+
+        if !self.match_token_type(TokenType::As) {
+            self.consume(TokenType::Semicolon, "Expect ';' after import.");
+            self.emit_bytes(OpCode::Import(path), OpCode::Pop);
+            return;
+        }
+        self.consume(TokenType::Identifier, "Expect module name after 'as'.");
+        let token = self.previous_token().expect("Expected previous token");
+        self.consume(TokenType::Semicolon, "Expect ';' after import.");
+
+        if *self.scope_depth.borrow() > 0 {
+            self.add_local(token);
+            self.emit_byte(OpCode::Import(path));
+            self.mark_initialized();
+        } else {
+            let global_slot = self.declared_global_slot(&token);
+            self.emit_bytes(OpCode::Import(path), OpCode::DefineGlobal(global_slot));
+        }
+    }
+
+    fn add_local(&self, token: Token<'a>) {
+        let depth = *self.scope_depth.borrow();
+        let already_declared = self
+            .locals
+            .borrow()
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|d| d >= depth))
+            .any(|local| local.token.lexeme == token.lexeme);
+        // Hidden locals have names in parentheses, which no identifier can
+        // clash with, and may well share them.
+        let already_declared = already_declared && !token.lexeme.starts_with('(');
+        if already_declared {
+            self.error_at(
+                Some(token.clone()),
+                "Already a variable with this name in this scope.",
+            );
+        }
+
+        if self.locals.borrow().len() > u8::MAX as usize {
+            self.error_at(Some(token), "Too many local variables in scope.");
+            return;
+        }
+        self.locals.borrow_mut().push(Local {
+            token,
+            depth: None,
+            is_captured: false,
+            is_const: false,
+        });
+    }
+
+    fn mark_initialized(&self) {
+        let depth = *self.scope_depth.borrow();
+        if let Some(local) = self.locals.borrow_mut().last_mut() {
+            local.depth = Some(depth);
+        }
+    }
+
+    fn advance(&self) {
+        *self.previous.borrow_mut() = self.current_token();
+
+        loop {
+            let token = self.scanner.scan_token();
+
+            if token.token_type != TokenType::Error {
+                *self.current.borrow_mut() = Some(token);
+                break;
+            }
+            self.error_at(Some(token.clone()), token.lexeme);
+        }
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current_token()
+            .is_some_and(|token| token.token_type == token_type)
+    }
+
+    fn match_token_type(&self, token_type: TokenType) -> bool {
+        if let Some(token) = &self.current_token() {
+            if token.token_type == token_type {
+                self.advance();
+                return true;
+            }
+        }
+        false
+    }
+
+    fn statement(&self) {
+        if self.match_token_type(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token_type(TokenType::For) {
+            self.for_statement();
+        } else if self.match_token_type(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token_type(TokenType::While) {
+            self.while_statement();
+        } else if self.match_token_type(TokenType::Break) {
+            self.break_statement();
+        } else if self.match_token_type(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.match_token_type(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token_type(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.match_token_type(TokenType::Try) {
+            self.try_statement();
+        } else if self.match_token_type(TokenType::Match) {
+            self.match_statement();
+        } else if self.check(TokenType::LeftBrace) && !self.starts_map_literal() {
+            self.advance();
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    // A `{` at the start of a statement opens a block unless it is followed
+    // by a key and a colon, as in `{"k": v}.keys();`. Map literals with
+    // longer key expressions have to be wrapped in parentheses there.
+    fn starts_map_literal(&self) -> bool {
+        let ahead = self.scanner.peek_tokens(2);
+        ahead[0].token_type != TokenType::RightBrace && ahead[1].token_type == TokenType::Colon
+    }
+
+    fn expression_statement(&self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::Pop);
+    }
+
+    fn block(&self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' at the end of scope");
+    }
+
+    fn begin_scope(&self) {
+        *self.scope_depth.borrow_mut() += 1;
+    }
+
+    fn end_scope(&self) {
+        *self.scope_depth.borrow_mut() -= 1;
+        let depth = *self.scope_depth.borrow();
+
+        while self
+            .locals
+            .borrow()
+            .last()
+            .is_some_and(|local| local.depth.is_none_or(|d| d > depth))
+        {
+            let local = self.locals.borrow_mut().pop().expect("Expected a local");
+            self.emit_byte(Self::discard_op(&local));
+        }
+    }
+
+    fn discard_op(local: &Local) -> OpCode {
+        if local.is_captured {
+            OpCode::CloseUpvalue
+        } else {
+            OpCode::Pop
+        }
+    }
+
+    // `for (var x in iterable) body` keeps the iterator in a hidden local
+    // and gives `x` a fresh scope on every iteration.
+    fn for_statement(&self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        self.consume(TokenType::Var, "Expect 'var' after '('.");
+        self.consume(TokenType::Identifier, "Expect loop variable name.");
+        let variable = self.previous_token().unwrap();
+        self.consume(TokenType::In, "Expect 'in' after loop variable.");
+
+        self.expression();
+        self.emit_byte(OpCode::GetIter);
+        let iterator = Token {
+            token_type: TokenType::Identifier,
+            line: variable.line,
+            lexeme: "(iterator)",
+        };
+        self.add_local(iterator);
+        self.mark_initialized();
+        let iterator_slot = (self.locals.borrow().len() - 1) as u8;
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+        let loop_start = self.chunk.borrow().code.len();
+        let exit_jump = self.emit_jump(OpCode::ForIter(iterator_slot, 0));
+        self.begin_loop(loop_start);
+
+        self.begin_scope();
+        self.add_local(variable);
+        self.mark_initialized();
+        self.statement();
+        self.end_scope();
+
+        self.emit_loop(loop_start);
+        self.patch_jump(exit_jump);
+        self.end_loop();
+        self.end_scope();
+    }
+
+    // `match subject { pattern => statement, ... }` runs the first arm whose
+    // pattern matches. The subject is kept in a hidden local and every test
+    // of a pattern reads it from there, jumping to the next arm on failure.
+    fn match_statement(&self) {
+        self.begin_scope();
+        let line = self.previous_token().unwrap().line;
+        self.expression();
+        let subject = Token {
+            token_type: TokenType::Identifier,
+            line,
+            lexeme: "(match)",
+        };
+        self.add_local(subject);
+        self.mark_initialized();
+        let subject_slot = (self.locals.borrow().len() - 1) as u8;
+        self.consume(TokenType::LeftBrace, "Expect '{' after match subject.");
+
+        let mut end_jumps = Vec::new();
+        let mut covered = false;
+        let mut seen_literals: Vec<String> = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            let arm = self.current_token();
+            self.begin_scope();
+            let mut pattern = Pattern::default();
+            let coverage = self.pattern(subject_slot, &[], &mut pattern);
+            self.consume(TokenType::Arrow, "Expect '=>' after match pattern.");
+
+            let unreachable = match &coverage {
+                _ if covered => true,
+                Coverage::Literals(literals) => literals.iter().all(|l| seen_literals.contains(l)),
+                _ => false,
+            };
+            if unreachable {
+                self.warning_at(arm, "Unreachable match arm.");
+            }
+            match coverage {
+                Coverage::All => covered = true,
+                Coverage::Literals(literals) => seen_literals.extend(literals),
+                Coverage::Partial => {}
+            }
+
+            for (name, path) in pattern.bindings {
+                self.emit_pattern_value(subject_slot, &path);
+                self.add_local(name);
+                self.mark_initialized();
             }
             self.statement();
+            self.end_scope();
+            end_jumps.push(self.emit_jump(OpCode::Jump(0)));
+
+            // A failed test leaves its `false` behind.
+            if !pattern.fail_jumps.is_empty() {
+                for jump in pattern.fail_jumps {
+                    self.patch_jump(jump);
+                }
+                self.emit_byte(OpCode::Pop);
+            }
+            self.match_token_type(TokenType::Comma);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after match arms.");
+
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    // Alternatives separated by `|`. Each one but the last jumps straight to
+    // the end of the pattern when it matches and falls through to the next
+    // one otherwise.
+    fn pattern(&self, subject_slot: u8, path: &[u8], pattern: &mut Pattern<'a>) -> Coverage {
+        let bindings = pattern.bindings.len();
+        let mut matched_jumps = Vec::new();
+        let mut coverage = None;
+
+        loop {
+            let mut alternative = Pattern::default();
+            let alternative_coverage = self.simple_pattern(subject_slot, path, &mut alternative);
+            pattern.bindings.append(&mut alternative.bindings);
+            coverage = Some(match coverage {
+                Some(coverage) => alternative_coverage.or(coverage),
+                None => alternative_coverage,
+            });
+
+            if !self.match_token_type(TokenType::Pipe) {
+                pattern.fail_jumps.append(&mut alternative.fail_jumps);
+                break;
+            }
+            matched_jumps.push(self.emit_jump(OpCode::Jump(0)));
+            for jump in alternative.fail_jumps {
+                self.patch_jump(jump);
+            }
+            self.emit_byte(OpCode::Pop);
+        }
+
+        if !matched_jumps.is_empty() && pattern.bindings.len() > bindings {
+            self.error_at(
+                self.previous_token(),
+                "Can't bind variables in alternative patterns.",
+            );
+        }
+        for jump in matched_jumps {
+            self.patch_jump(jump);
+        }
+        coverage.unwrap()
+    }
+
+    fn simple_pattern(&self, subject_slot: u8, path: &[u8], pattern: &mut Pattern<'a>) -> Coverage {
+        if self.match_token_type(TokenType::Identifier) {
+            let name = self.previous_token().unwrap();
+            if name.lexeme != "_" {
+                pattern.bindings.push((name, path.to_vec()));
+            }
+            return Coverage::All;
+        }
+
+        if self.match_token_type(TokenType::LeftBracket) {
+            self.emit_pattern_value(subject_slot, path);
+            // The length is patched in once the elements have been parsed.
+            self.emit_byte(OpCode::MatchList(0));
+            let check = self.chunk.borrow().code.len() - 1;
+            self.emit_pattern_test(pattern);
+
+            let mut count: u8 = 0;
+            if !self.check(TokenType::RightBracket) {
+                loop {
+                    let mut element = path.to_vec();
+                    element.push(count);
+                    self.pattern(subject_slot, &element, pattern);
+                    if count == u8::MAX {
+                        self.error_at(self.previous_token(), "Too many elements in list pattern.");
+                    }
+                    count = count.wrapping_add(1);
+                    if !self.match_token_type(TokenType::Comma) {
+                        break;
+                    }
+                }
+            }
+            self.consume(TokenType::RightBracket, "Expect ']' after list pattern.");
+            self.chunk.borrow_mut().code[check] = OpCode::MatchList(count);
+            return Coverage::Partial;
+        }
+
+        self.emit_pattern_value(subject_slot, path);
+        let negative = self.match_token_type(TokenType::Minus);
+        self.advance();
+        let literal = self.previous_token().unwrap();
+        match literal.token_type {
+            TokenType::Number => Parser::number(self, false),
+            TokenType::String if !negative => Parser::string(self, false),
+            TokenType::True | TokenType::False | TokenType::Nil if !negative => {
+                Parser::literal(self, false)
+            }
+            _ => {
+                self.error_at(Some(literal), "Expect pattern.");
+                return Coverage::Partial;
+            }
+        }
+        if negative {
+            self.emit_byte(OpCode::Negate);
+        }
+        self.emit_byte(OpCode::Equal);
+        self.emit_pattern_test(pattern);
+
+        let sign = if negative { "-" } else { "" };
+        Coverage::Literals(vec![format!("{}{}", sign, literal.lexeme)])
+    }
+
+    // Pushes the part of the subject a pattern is matched against.
+    fn emit_pattern_value(&self, subject_slot: u8, path: &[u8]) {
+        self.emit_byte(OpCode::GetLocal(subject_slot));
+        for index in path {
+            self.emit_constant(Value::Int(*index as i64));
+            self.emit_byte(OpCode::GetIndex);
+        }
+    }
+
+    fn emit_pattern_test(&self, pattern: &mut Pattern<'a>) {
+        pattern
+            .fail_jumps
+            .push(self.emit_jump(OpCode::JumpIfFalse(0)));
+        self.emit_byte(OpCode::Pop);
+    }
+
+    fn while_statement(&self) {
+        let loop_start = self.chunk.borrow().code.len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse(0));
+        self.emit_byte(OpCode::Pop);
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop);
+        self.end_loop();
+    }
+
+    fn if_statement(&self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse(0));
+        self.emit_byte(OpCode::Pop);
+        self.statement();
+        let else_jump = self.emit_jump(OpCode::Jump(0));
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop);
+        if self.match_token_type(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn begin_loop(&self, start: usize) {
+        self.loops.borrow_mut().push(Loop {
+            start,
+            scope_depth: *self.scope_depth.borrow(),
+            handler_count: self.handlers.borrow().len(),
+            break_jumps: Vec::new(),
+        });
+    }
+
+    // Sends every `break` of the innermost loop to the next instruction.
+    fn end_loop(&self) {
+        let finished = self.loops.borrow_mut().pop().expect("Expected a loop");
+        for jump in finished.break_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_statement(&self) {
+        let keyword = self.previous_token();
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+        let Some((depth, handler_count)) = self
+            .loops
+            .borrow()
+            .last()
+            .map(|l| (l.scope_depth, l.handler_count))
+        else {
+            self.error_at(keyword, "Can't use 'break' outside of a loop.");
+            return;
+        };
+
+        self.emit_break(depth, handler_count);
+    }
+
+    fn emit_break(&self, depth: u8, handler_count: usize) {
+        if !self.leave_handlers(handler_count, Exit::Break) {
+            return;
+        }
+        self.discard_locals(depth);
+        let jump = self.emit_jump(OpCode::Jump(0));
+        if let Some(innermost) = self.loops.borrow_mut().last_mut() {
+            innermost.break_jumps.push(jump);
+        }
+    }
+
+    fn continue_statement(&self) {
+        let keyword = self.previous_token();
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+        let Some((start, depth, handler_count)) = self
+            .loops
+            .borrow()
+            .last()
+            .map(|l| (l.start, l.scope_depth, l.handler_count))
+        else {
+            self.error_at(keyword, "Can't use 'continue' outside of a loop.");
+            return;
+        };
+
+        self.emit_continue(start, depth, handler_count);
+    }
+
+    fn emit_continue(&self, start: usize, depth: u8, handler_count: usize) {
+        if !self.leave_handlers(handler_count, Exit::Continue) {
+            return;
+        }
+        self.discard_locals(depth);
+        self.emit_loop(start);
+    }
+
+    // Pops the locals deeper than `depth` off the runtime stack without
+    // forgetting them, since the code after a `break` or `continue` is still
+    // inside their scope.
+    fn discard_locals(&self, depth: u8) {
+        let ops: Vec<OpCode> = self
+            .locals
+            .borrow()
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|d| d > depth))
+            .map(Self::discard_op)
+            .collect();
+        for op in ops {
+            self.emit_byte(op);
+        }
+    }
+
+    // Removes the exception handlers installed since the first `count`, on
+    // the way out of the `try` statements in between. The first `finally`
+    // block on the way is entered with the exit pending, and the exit
+    // continues from the end of the block. Returns true when no block is in
+    // the way and the caller has to complete the exit itself.
+    fn leave_handlers(&self, count: usize, exit: Exit) -> bool {
+        let active = self.handlers.borrow().len();
+        for index in (count..active).rev() {
+            self.emit_byte(OpCode::PopHandler);
+            let (is_finally, local_count) = {
+                let handler = &self.handlers.borrow()[index];
+                (handler.is_finally, handler.local_count)
+            };
+            if !is_finally {
+                continue;
+            }
+
+            // The block expects the locals outside the `try` statement,
+            // then the return value, if any, and the exit.
+            let ops: Vec<OpCode> = self.locals.borrow()[local_count..]
+                .iter()
+                .rev()
+                .map(Self::discard_op)
+                .collect();
+            if exit == Exit::Return && !ops.is_empty() {
+                self.emit_byte(OpCode::Bury(ops.len() as u8));
+            }
+            for op in ops {
+                self.emit_byte(op);
+            }
+            if exit != Exit::Return {
+                self.emit_byte(OpCode::Nil);
+            }
+            self.emit_constant(Value::Int(exit as i64));
+            let jump = self.emit_jump(OpCode::Jump(0));
+            self.handlers.borrow_mut()[index].exits.push((exit, jump));
+            return false;
         }
+        true
+    }
+
+    // Returns the value on top of the stack.
+    fn emit_return(&self) {
+        if self.leave_handlers(0, Exit::Return) {
+            self.emit_byte(OpCode::Return);
+        }
+    }
+
+    fn return_statement(&self) {
+        let keyword = self.previous_token();
+        if self.enclosing.borrow().is_empty() {
+            self.error_at(keyword.clone(), "Can't return from top-level code.");
+        }
+
+        if self.match_token_type(TokenType::Semicolon) {
+            self.emit_implicit_result();
+        } else {
+            match *self.function_kind.borrow() {
+                FunctionKind::Initializer => {
+                    self.error_at(keyword.clone(), "Can't return a value from an initializer.")
+                }
+                FunctionKind::Setter => {
+                    self.error_at(keyword.clone(), "Can't return a value from a setter.")
+                }
+                _ => {}
+            }
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+        }
+        self.emit_return();
+    }
+
+    fn throw_statement(&self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+        self.emit_byte(OpCode::Throw);
+    }
+
+    // try { A } catch (e) { B } finally { C }
+    //
+    //               PushHandler -> on_exception   (with finally)
+    //               PushHandler -> catch          (with catch)
+    //               A
+    //               PopHandler                    (per handler)
+    //               Jump -> normal_exit
+    // catch:        B with `e` bound to the exception
+    //               PopHandler                    (with finally)
+    //               Jump -> normal_exit
+    // on_exception: True
+    //               Jump -> finally
+    // normal_exit:  Nil
+    //               False
+    // finally:      C
+    //               (per exit)    continue the exit if it is pending
+    //               EndFinally
+    //
+    // Without a `finally` clause `normal_exit` directly follows the catch.
+    // A `return`, `break` or `continue` in A or B jumps to `finally` with
+    // its exit pending, see `leave_handlers`.
+    fn try_statement(&self) {
+        let (has_catch, has_finally) = self.scanner.try_clauses();
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.");
+
+        let finally_handler = has_finally.then(|| self.push_handler(true));
+        let catch_handler = has_catch.then(|| self.push_handler(false));
+
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+
+        let mut exit_jumps = Vec::new();
+        let mut pending_exits = Vec::new();
+        if let Some(catch_handler) = catch_handler {
+            self.consume(TokenType::Catch, "Expect 'catch' after 'try' block.");
+            self.pop_handler();
+            if has_finally {
+                pending_exits.extend(self.pop_handler());
+            }
+            exit_jumps.push(self.emit_jump(OpCode::Jump(0)));
+            self.patch_jump(catch_handler);
+            if has_finally {
+                // Still active for the catch clause, without an instruction
+                // of its own.
+                self.handlers.borrow_mut().push(self.handler(true));
+            }
+            self.catch_clause();
+            if has_finally {
+                pending_exits.extend(self.pop_handler());
+            }
+        } else if has_finally {
+            pending_exits.extend(self.pop_handler());
+        }
+
+        if let Some(finally_handler) = finally_handler {
+            exit_jumps.push(self.emit_jump(OpCode::Jump(0)));
+            self.patch_jump(finally_handler);
+            self.emit_byte(OpCode::True);
+            let finally_jump = self.emit_jump(OpCode::Jump(0));
+            for jump in exit_jumps.drain(..) {
+                self.patch_jump(jump);
+            }
+            self.emit_bytes(OpCode::Nil, OpCode::False);
+            self.patch_jump(finally_jump);
+            for (_, jump) in &pending_exits {
+                self.patch_jump(*jump);
+            }
+            self.consume(TokenType::Finally, "Expect 'finally' after 'catch' block.");
+            let mut exits = Vec::new();
+            for (exit, _) in pending_exits {
+                if !exits.contains(&exit) {
+                    exits.push(exit);
+                }
+            }
+            self.finally_clause(&exits);
+        } else if !has_catch {
+            self.error_at(
+                self.current_token(),
+                "Expect 'catch' or 'finally' after 'try' block.",
+            );
+        }
+        for jump in exit_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn push_handler(&self, is_finally: bool) -> usize {
+        self.handlers.borrow_mut().push(self.handler(is_finally));
+        self.emit_jump(OpCode::PushHandler(0))
+    }
+
+    fn handler(&self, is_finally: bool) -> Handler {
+        Handler {
+            is_finally,
+            local_count: self.locals.borrow().len(),
+            exits: Vec::new(),
+        }
+    }
+
+    // Returns the exits that are pending in the handler's `finally` block.
+    fn pop_handler(&self) -> Vec<(Exit, usize)> {
+        let handler = self
+            .handlers
+            .borrow_mut()
+            .pop()
+            .expect("Expected a handler");
+        self.emit_byte(OpCode::PopHandler);
+        handler.exits
+    }
+
+    // The VM pushes the exception before jumping here, which makes it the
+    // next local slot.
+    fn catch_clause(&self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.");
+        self.consume(TokenType::Identifier, "Expect exception variable name.");
+        let variable = self.previous_token().unwrap();
+        self.consume(
+            TokenType::RightParen,
+            "Expect ')' after exception variable.",
+        );
+        self.consume(TokenType::LeftBrace, "Expect '{' before catch body.");
+
+        self.begin_scope();
+        self.add_local(variable);
+        self.mark_initialized();
+        self.block();
+        self.end_scope();
     }
 
-    fn var_declaration(&'a self) {
-        self.consume(TokenType::Identifier, "Expect variable name.");
+    // Runs with the pending exception and the flag telling whether to throw
+    // it again in two hidden locals, which `EndFinally` pops. A pending exit
+    // puts its return value, or nil, and its `Exit` there instead, and is
+    // continued once the block is done.
+    fn finally_clause(&self, exits: &[Exit]) {
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'finally'.");
+        self.begin_scope();
+        let line = self.previous_token().map_or(0, |token| token.line);
+        for lexeme in ["(exception)", "(rethrow)"] {
+            self.add_local(Token {
+                token_type: TokenType::Identifier,
+                line,
+                lexeme,
+            });
+            self.mark_initialized();
+        }
 
-        let token = self.previous_token().expect("Expected previous token");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
 
-        // TODO: implement with scope depth in mind
-        // self.locals.borrow_mut().push(token.clone());
+        let hidden = self.locals.borrow().len() - 2;
+        for exit in exits {
+            self.emit_pending_exit(*exit, hidden as u8);
+        }
 
-        let variable_index = self
-            .chunk
-            .borrow_mut()
-            .add_constant(Value::String(token.lexeme.to_string()));
+        *self.scope_depth.borrow_mut() -= 1;
+        self.locals.borrow_mut().truncate(hidden);
+        self.emit_byte(OpCode::EndFinally);
+    }
 
-        if self.match_token_type(TokenType::Equal) {
-            self.expression();
-        } else {
-            self.emit_byte(OpCode::Nil);
+    // Continues the exit when the flag in the second of the two hidden
+    // locals starting at `slot` says it is pending.
+    fn emit_pending_exit(&self, exit: Exit, slot: u8) {
+        self.emit_byte(OpCode::GetLocal(slot + 1));
+        self.emit_constant(Value::Int(exit as i64));
+        self.emit_byte(OpCode::Equal);
+        let skip = self.emit_jump(OpCode::JumpIfFalse(0));
+        self.emit_byte(OpCode::Pop);
+
+        let innermost = self
+            .loops
+            .borrow()
+            .last()
+            .map(|l| (l.start, l.scope_depth, l.handler_count));
+        match (exit, innermost) {
+            (Exit::Return, _) => {
+                self.emit_byte(OpCode::GetLocal(slot));
+                self.emit_return();
+            }
+            (Exit::Break, Some((_, depth, handler_count))) => self.emit_break(depth, handler_count),
+            (Exit::Continue, Some((start, depth, handler_count))) => {
+                self.emit_continue(start, depth, handler_count)
+            }
+            _ => unreachable!("Expected a loop"),
         }
-        self.consume(
-            TokenType::Semicolon,
-            "Expect ';' after variable declaration.",
-        );
-        self.emit_byte(OpCode::DefineGlobal(variable_index));
+
+        self.patch_jump(skip);
+        self.emit_byte(OpCode::Pop);
     }
 
-    fn advance(&self) {
-        *self.previous.borrow_mut() = self.current_token();
+    fn function(&self, name: Option<&str>, kind: FunctionKind) {
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        self.function_after_paren(name, kind);
+    }
 
-        loop {
-            let token = self.scanner.scan_token();
+    // Compiles a parameter list and body into a function constant and emits
+    // the instruction that wraps it in a closure. The body is either a block
+    // or, after `=>`, a single expression whose value is returned. Getters
+    // have no parameter list, so for them there is no paren to be after.
+    fn function_after_paren(&self, name: Option<&str>, kind: FunctionKind) {
+        self.begin_function(kind);
+        self.begin_scope();
 
-            if token.token_type != TokenType::Error {
-                *self.current.borrow_mut() = Some(token);
-                break;
+        let mut arity: usize = 0;
+        let mut optional: usize = 0;
+        let mut variadic = false;
+        let mut parameters = Vec::new();
+        let mut destructured = Vec::new();
+        if kind != FunctionKind::Getter && !self.check(TokenType::RightParen) {
+            loop {
+                if arity == u8::MAX as usize {
+                    self.error_at(self.current_token(), "Can't have more than 255 parameters.");
+                }
+                if self.match_token_type(TokenType::DotDotDot) {
+                    self.consume(TokenType::Identifier, "Expect parameter name after '...'.");
+                    self.add_local(self.previous_token().expect("Expected previous token"));
+                    self.mark_initialized();
+                    variadic = true;
+                    if !self.check(TokenType::RightParen) {
+                        self.error_at(self.current_token(), "Rest parameter must be last.");
+                    }
+                    break;
+                }
+                arity += 1;
+                parameters.push(self.current_token().unwrap().lexeme.to_string());
+                if self.match_token_type(TokenType::LeftBracket)
+                    || self.match_token_type(TokenType::LeftBrace)
+                {
+                    // The argument stays in a hidden slot and is unpacked
+                    // once all parameters are known.
+                    let parameter = Token {
+                        token_type: TokenType::Identifier,
+                        line: self.previous_token().unwrap().line,
+                        lexeme: "(parameter)",
+                    };
+                    self.add_local(parameter);
+                    self.mark_initialized();
+                    let slot = (self.locals.borrow().len() - 1) as u8;
+                    destructured.push((slot, self.destructuring_pattern()));
+                } else {
+                    self.consume(TokenType::Identifier, "Expect parameter name.");
+                    self.add_local(self.previous_token().expect("Expected previous token"));
+                    self.mark_initialized();
+                }
+
+                if self.match_token_type(TokenType::Equal) {
+                    optional += 1;
+                    self.default_value((arity - 1) as u8);
+                } else if optional > 0 {
+                    self.error_at(
+                        self.previous_token(),
+                        "Parameters without a default can't follow ones with a default.",
+                    );
+                }
+                if !self.match_token_type(TokenType::Comma) {
+                    break;
+                }
             }
         }
-    }
+        if kind != FunctionKind::Getter {
+            self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        }
+        if kind == FunctionKind::Setter && (arity != 1 || optional > 0 || variadic) {
+            self.error_at(
+                self.previous_token(),
+                "A setter must have exactly one parameter.",
+            );
+        }
 
-    fn match_token_type(&'a self, token_type: TokenType) -> bool {
-        if let Some(token) = &self.current_token() {
-            if token.token_type == token_type {
-                self.advance();
-                return true;
+        for (slot, (is_list, names)) in destructured {
+            self.emit_byte(OpCode::GetLocal(slot));
+            self.emit_unpack(is_list, &names);
+            for name in names {
+                self.add_local(name);
+                self.mark_initialized();
             }
         }
-        false
-    }
 
-    fn statement(&'a self) {
-        if self.match_token_type(TokenType::Print) {
-            self.print_statement();
-        } else if self.match_token_type(TokenType::LeftBrace) {
+        if self.match_token_type(TokenType::Arrow) {
+            if self.check(TokenType::LeftBrace) && !self.starts_map_literal() {
+                self.advance();
+                self.block();
+                self.emit_implicit_result();
+            } else {
+                self.expression();
+                if matches!(kind, FunctionKind::Initializer | FunctionKind::Setter) {
+                    self.emit_byte(OpCode::Pop);
+                    self.emit_implicit_result();
+                }
+            }
+        } else {
+            self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
             self.block();
+            self.emit_implicit_result();
         }
+        self.emit_byte(OpCode::Return);
+
+        let (chunk, upvalues) = self.end_function();
+        let function = LoxFunction {
+            kind,
+            name: name.map(str::to_string),
+            arity: arity as u8,
+            optional: optional as u8,
+            variadic,
+            parameters,
+            upvalues,
+            chunk,
+        };
+        let function = {
+            let mut heap = self.heap.borrow_mut();
+            self.collect_garbage(&mut heap, &function.chunk.constants);
+            heap.alloc(Obj::Function(function))
+        };
+        let constant = self.make_constant(Value::Obj(function));
+        self.emit_byte(OpCode::Closure(constant));
     }
 
-    fn block(&'a self){
-        // begin scope
-        *self.scope_depth.borrow_mut() += 1;
+    // Defaults are computed when the function is called, in the function's
+    // own scope, so they can refer to the parameters before them.
+    fn default_value(&self, parameter: u8) {
+        let slot = (self.locals.borrow().len() - 1) as u8;
+        let skip = self.emit_jump(OpCode::JumpIfSupplied(parameter, 0));
+        self.expression();
+        self.emit_bytes(OpCode::SetLocal(slot), OpCode::Pop);
+        self.patch_jump(skip);
+    }
 
+    // Pushes what a function returns when it doesn't return a value itself:
+    // nil, except for initializers and setters.
+    fn emit_implicit_result(&self) {
+        self.emit_byte(match *self.function_kind.borrow() {
+            FunctionKind::Initializer => OpCode::GetLocal(0),
+            FunctionKind::Setter => OpCode::GetLocal(1),
+            _ => OpCode::Nil,
+        });
+    }
+
+    // Puts the enclosing function aside and starts on an empty one.
+    fn begin_function(&self, kind: FunctionKind) {
+        let slot_zero = match kind {
+            FunctionKind::Script | FunctionKind::Function => Self::callee_slot(),
+            _ => Self::receiver_slot(),
+        };
+        let state = FunctionState {
+            chunk: mem::take(&mut **self.chunk.borrow_mut()),
+            locals: mem::replace(&mut *self.locals.borrow_mut(), vec![slot_zero]),
+            upvalues: mem::take(&mut *self.upvalues.borrow_mut()),
+            scope_depth: mem::take(&mut *self.scope_depth.borrow_mut()),
+            loops: mem::take(&mut *self.loops.borrow_mut()),
+            handlers: mem::take(&mut *self.handlers.borrow_mut()),
+            kind: mem::replace(&mut *self.function_kind.borrow_mut(), kind),
+        };
+        self.enclosing.borrow_mut().push(state);
+    }
 
-        // TODO: Continue here with scopes
-        // end scope
-        // *self.scope_depth.borrow_mut() -= 1;
-        // while *self.local_count.borrow() > 0 && self.locals[self.
+    // Restores the enclosing function and hands back the finished code and
+    // what it captures.
+    fn end_function(&self) -> (Chunk, Vec<UpvalueSource>) {
+        let state = self
+            .enclosing
+            .borrow_mut()
+            .pop()
+            .expect("Expected an enclosing function");
+        *self.locals.borrow_mut() = state.locals;
+        *self.scope_depth.borrow_mut() = state.scope_depth;
+        *self.loops.borrow_mut() = state.loops;
+        *self.handlers.borrow_mut() = state.handlers;
+        *self.function_kind.borrow_mut() = state.kind;
+        let upvalues = mem::replace(&mut *self.upvalues.borrow_mut(), state.upvalues);
+        let chunk = mem::replace(&mut **self.chunk.borrow_mut(), state.chunk);
+        (chunk, upvalues)
+    }
 
-        self.consume(TokenType::RightBrace, "Expect '}' at the end of scope");
+    fn emit_jump(&self, opcode: OpCode) -> usize {
+        self.emit_byte(opcode);
+        self.chunk.borrow().code.len() - 1
+    }
+
+    // Points the jump at `offset` to the next instruction to be emitted.
+    fn patch_jump(&self, offset: usize) {
+        let jump = self.chunk.borrow().code.len() - offset - 1;
+        let Ok(jump) = u16::try_from(jump) else {
+            self.error_at(self.previous_token(), "Too much code to jump over.");
+            return;
+        };
 
+        let mut chunk = self.chunk.borrow_mut();
+        chunk.code[offset] = match chunk.code[offset] {
+            OpCode::Jump(_) => OpCode::Jump(jump),
+            OpCode::JumpIfFalse(_) => OpCode::JumpIfFalse(jump),
+            OpCode::JumpIfNotNil(_) => OpCode::JumpIfNotNil(jump),
+            OpCode::ForIter(slot, _) => OpCode::ForIter(slot, jump),
+            OpCode::PushHandler(_) => OpCode::PushHandler(jump),
+            OpCode::JumpIfSupplied(parameter, _) => OpCode::JumpIfSupplied(parameter, jump),
+            opcode => unreachable!("Cannot patch {:?}", opcode),
+        };
+    }
+
+    fn emit_loop(&self, loop_start: usize) {
+        let offset = self.chunk.borrow().code.len() + 1 - loop_start;
+        match u16::try_from(offset) {
+            Ok(offset) => self.emit_byte(OpCode::Loop(offset)),
+            Err(_) => self.error_at(self.previous_token(), "Loop body too large."),
+        }
     }
 
-    fn print_statement(&'a self) {
+    fn print_statement(&self) {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
         self.emit_byte(OpCode::Print);
     }
 
-    fn consume(&'a self, token_type: TokenType, message: &str) {
+    fn consume(&self, token_type: TokenType, message: &str) {
         if let Some(token) = self.current_token() {
             if token.token_type == token_type {
                 self.advance();
@@ -185,13 +1563,101 @@ impl<'a> Parser<'a> {
         self.error_at(self.current_token(), message);
     }
 
+    // For instructions whose operand can only address the first 256
+    // constants.
+    fn make_constant(&self, value: Value) -> u8 {
+        let constant = self.chunk.borrow_mut().add_constant(value);
+        u8::try_from(constant).unwrap_or_else(|_| {
+            self.error_at(self.previous_token(), "Too many constants in one chunk.");
+            0
+        })
+    }
+
+    // Loads a value, with `ConstantLong` once the short form runs out.
+    fn emit_constant(&self, value: Value) {
+        let constant = self.chunk.borrow_mut().add_constant(value);
+        if let Ok(constant) = u8::try_from(constant) {
+            self.emit_byte(OpCode::Constant(constant));
+        } else if let Ok(constant) = u16::try_from(constant) {
+            self.emit_byte(OpCode::ConstantLong(constant));
+        } else {
+            self.error_at(self.previous_token(), "Too many constants in one chunk.");
+        }
+    }
+
+    fn identifier_constant(&self, token: &Token) -> u8 {
+        let name = self.intern(token.lexeme.to_string());
+        self.make_constant(Value::Obj(name))
+    }
+
+    fn global_slot(&self, token: &Token) -> u16 {
+        let name = self.intern(token.lexeme.to_string());
+        let slot = self.globals.borrow_mut().resolve(name);
+        slot.unwrap_or_else(|| {
+            self.error_at(Some(token.clone()), "Too many global variables.");
+            0
+        })
+    }
+
+    // The slot of a global being declared, which must not be a constant
+    // already.
+    fn declared_global_slot(&self, token: &Token) -> u16 {
+        let slot = self.global_slot(token);
+        if self.globals.borrow().is_const(slot) {
+            self.error_at(Some(token.clone()), "Already a constant with this name.");
+        }
+        slot
+    }
+
+    fn intern(&self, string: String) -> ObjRef {
+        let mut heap = self.heap.borrow_mut();
+        if let Some(handle) = heap.find_string(&string) {
+            return handle;
+        }
+        self.collect_garbage(&mut heap, &[]);
+        heap.intern(string)
+    }
+
+    fn intern_big_int(&self, int: BigInt) -> ObjRef {
+        let mut heap = self.heap.borrow_mut();
+        if let Some(handle) = heap.find_big_int(&int) {
+            return handle;
+        }
+        self.collect_garbage(&mut heap, &[]);
+        heap.intern_big_int(int)
+    }
+
+    // Constants are only reachable through the chunks being compiled, so
+    // they are marked here together with what the VM holds on to and the
+    // `pending` values that are about to be stored.
+    fn collect_garbage(&self, heap: &mut Heap, pending: &[Value]) {
+        if !heap.should_collect() {
+            return;
+        }
+        for value in self.vm_roots.iter().chain(pending) {
+            heap.mark_value(value);
+        }
+        self.globals.borrow().mark(heap);
+        for value in &self.chunk.borrow().constants {
+            heap.mark_value(value);
+        }
+        for state in self.enclosing.borrow().iter() {
+            for value in &state.chunk.constants {
+                heap.mark_value(value);
+            }
+        }
+        heap.collect();
+    }
+
     fn emit_bytes(&self, byte1: OpCode, byte2: OpCode) {
         self.emit_byte(byte1);
         self.emit_byte(byte2);
     }
 
     fn emit_byte(&self, opcode: OpCode) {
-        self.chunk.borrow_mut().write(opcode);
+        let line = self.previous_token().map_or(0, |token| token.line);
+        self.chunk.borrow_mut().write(opcode, line);
+        self.target.replace(None);
     }
 
     fn expression(&self) {
@@ -204,6 +1670,7 @@ impl<'a> Parser<'a> {
         }
 
         *self.panic_mode.borrow_mut() = true;
+        *self.had_error.borrow_mut() = true;
 
         match token {
             Some(token) => {
@@ -218,19 +1685,29 @@ impl<'a> Parser<'a> {
             }
             _ => return,
         };
-        eprint!(": {}", message);
-        *self.panic_mode.borrow_mut() = true;
+        eprintln!(": {}", message);
+    }
+
+    // Reported like an error, but compilation carries on and succeeds.
+    fn warning_at(&self, token: Option<Token>, message: &str) {
+        if let Some(token) = token {
+            eprintln!(
+                "[line {}] Warning at '{}': {}",
+                token.line, token.lexeme, message
+            );
+        }
     }
 
     fn parse_precedence(&self, precedence: Precedence) {
         self.advance();
 
         let previous_token_type = self.previous_token().unwrap().token_type;
+        let can_assign = precedence <= Precedence::Assignment;
 
         // Handle the prefix rule
         if let Some(rule) = self.get_rule(&previous_token_type) {
             if let Some(prefix_rule) = rule.prefix {
-                prefix_rule(self);
+                prefix_rule(self, can_assign);
             } else {
                 self.error_at(self.previous_token(), "Expected an expression.");
                 return;
@@ -252,22 +1729,374 @@ impl<'a> Parser<'a> {
 
             if let Some(rule) = self.get_rule(&previous_token_type) {
                 if let Some(infix_rule) = rule.infix {
-                    infix_rule(self);
+                    infix_rule(self, can_assign);
                 }
             }
         }
+
+        // Assignable expressions consume an assignment operator themselves,
+        // so one that is still left over follows something else.
+        if can_assign && self.check_assignment_operator() {
+            self.error_at(self.current_token(), "Invalid assignment target.");
+            self.advance();
+        }
+    }
+
+    fn check_assignment_operator(&self) -> bool {
+        [
+            TokenType::Equal,
+            TokenType::PlusEqual,
+            TokenType::MinusEqual,
+            TokenType::StarEqual,
+            TokenType::SlashEqual,
+            TokenType::PercentEqual,
+            TokenType::PlusPlus,
+            TokenType::MinusMinus,
+        ]
+        .into_iter()
+        .any(|token_type| self.check(token_type))
     }
 
     fn get_rule(&self, token_type: &TokenType) -> Option<&ParseRule> {
         RULES.get(token_type)
     }
 
-    fn grouping(parser: &Parser) {}
-    fn unary(parser: &Parser) {}
+    fn grouping(parser: &Parser, _can_assign: bool) {
+        if parser.starts_arrow_function() {
+            parser.function_after_paren(None, FunctionKind::Function);
+            return;
+        }
+        parser.expression();
+        parser.consume(TokenType::RightParen, "Expect ')' after expression.");
+        // A parenthesized variable is a value, not something to assign.
+        parser.target.replace(None);
+    }
+
+    // `(` starts an arrow function when a parameter list and `=>` follow.
+    fn starts_arrow_function(&self) -> bool {
+        if self.check(TokenType::RightParen) {
+            return self.scanner.peek_tokens(1)[0].token_type == TokenType::Arrow;
+        }
+        let first = self.current_token().unwrap().token_type;
+        self.scanner.closes_parameter_list(first)
+    }
+
+    fn lambda(parser: &Parser, _can_assign: bool) {
+        parser.function(None, FunctionKind::Function);
+    }
+
+    fn call(parser: &Parser, _can_assign: bool) {
+        let call = parser.argument_list();
+        parser.emit_byte(call);
+    }
+
+    fn unary(parser: &Parser, _can_assign: bool) {
+        let operator_type = parser.previous_token().unwrap().token_type;
+        parser.parse_precedence(Precedence::Unary);
+
+        match operator_type {
+            TokenType::Bang => parser.emit_byte(OpCode::Not),
+            TokenType::Minus => parser.emit_byte(OpCode::Negate),
+            TokenType::Tilde => parser.emit_byte(OpCode::BitNot),
+            _ => {}
+        }
+    }
+
+    fn list(parser: &Parser, _can_assign: bool) {
+        let mut count: usize = 0;
+        if !parser.check(TokenType::RightBracket) {
+            loop {
+                parser.expression();
+                if count == u8::MAX as usize {
+                    parser.error_at(
+                        parser.previous_token(),
+                        "Can't have more than 255 elements in a list literal.",
+                    );
+                }
+                count += 1;
+                if !parser.match_token_type(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        parser.consume(TokenType::RightBracket, "Expect ']' after list elements.");
+        parser.emit_byte(OpCode::BuildList(count as u8));
+    }
+
+    fn subscript(parser: &Parser, can_assign: bool) {
+        parser.expression();
+        parser.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if !parser.assignment(Target::Index, can_assign) {
+            parser.emit_target_get(Target::Index);
+        }
+    }
+
+    fn map(parser: &Parser, _can_assign: bool) {
+        let mut count: usize = 0;
+        if !parser.check(TokenType::RightBrace) {
+            loop {
+                parser.expression();
+                parser.consume(TokenType::Colon, "Expect ':' after map key.");
+                parser.expression();
+                if count == u8::MAX as usize {
+                    parser.error_at(
+                        parser.previous_token(),
+                        "Can't have more than 255 entries in a map literal.",
+                    );
+                }
+                count += 1;
+                if !parser.match_token_type(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        parser.consume(TokenType::RightBrace, "Expect '}' after map entries.");
+        parser.emit_byte(OpCode::BuildMap(count as u8));
+    }
+
+    fn dot(parser: &Parser, can_assign: bool) {
+        parser.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = parser.identifier_constant(&parser.previous_token().unwrap());
+
+        if parser.match_token_type(TokenType::LeftParen) {
+            // `Invoke` only takes plain arguments, so the method is looked
+            // up as a property first for the other kinds of calls.
+            let first = parser.current_token().unwrap().token_type;
+            if parser.scanner.argument_kinds(first) != (false, false) {
+                parser.emit_byte(OpCode::GetProperty(name));
+                let call = parser.argument_list();
+                parser.emit_byte(call);
+                return;
+            }
+            match parser.argument_list() {
+                OpCode::Call(arg_count) => parser.emit_byte(OpCode::Invoke(name, arg_count)),
+                _ => unreachable!("Expected plain arguments"),
+            }
+        } else if !parser.assignment(Target::Property(name), can_assign) {
+            parser.emit_target_get(Target::Property(name));
+        }
+    }
+
+    // Compiles `=`, a compound assignment or a postfix `++`/`--` after a
+    // target whose operands are on the stack already. Returns false, having
+    // emitted nothing, when none of them follows. Postfix operators bind
+    // tighter than any binary operator, so they don't depend on `can_assign`.
+    fn assignment(&self, target: Target, can_assign: bool) -> bool {
+        let operands = target.operands();
+        let Some(token_type) = self.current_token().map(|token| token.token_type) else {
+            return false;
+        };
+
+        match token_type {
+            TokenType::Equal if can_assign => {
+                self.advance();
+                self.check_assignable(target);
+                self.expression();
+            }
+            TokenType::PlusEqual
+            | TokenType::MinusEqual
+            | TokenType::StarEqual
+            | TokenType::SlashEqual
+            | TokenType::PercentEqual
+                if can_assign =>
+            {
+                self.advance();
+                self.check_assignable(target);
+                self.duplicate_operands(operands);
+                self.emit_byte(target.get());
+                self.expression();
+                self.emit_byte(match token_type {
+                    TokenType::PlusEqual => OpCode::Add,
+                    TokenType::MinusEqual => OpCode::Subtract,
+                    TokenType::StarEqual => OpCode::Multiply,
+                    TokenType::SlashEqual => OpCode::Divide,
+                    _ => OpCode::Modulo,
+                });
+            }
+            // The old value is tucked below the operands, which the store
+            // consumes, and stays behind once the new value is popped.
+            TokenType::PlusPlus | TokenType::MinusMinus => {
+                self.advance();
+                self.check_assignable(target);
+                self.duplicate_operands(operands);
+                self.emit_byte(target.get());
+                if operands == 0 {
+                    self.emit_byte(OpCode::Dup(0));
+                } else {
+                    self.emit_bytes(OpCode::Bury(operands), OpCode::Dup(operands));
+                }
+                self.emit_step(token_type);
+                self.emit_bytes(target.set(), OpCode::Pop);
+                return true;
+            }
+            _ => return false,
+        }
+        self.emit_byte(target.set());
+        true
+    }
+
+    // Reports assignments to constants, known by the instruction that will
+    // store the new value.
+    fn check_assignable(&self, target: Target) {
+        let name = match target {
+            Target::Local(slot) => {
+                let locals = self.locals.borrow();
+                let local = &locals[slot as usize];
+                local.is_const.then(|| local.token.lexeme.to_string())
+            }
+            Target::Upvalue(index) => self.const_upvalue(self.enclosing.borrow().len(), index),
+            Target::Global(slot) => {
+                let globals = self.globals.borrow();
+                globals.is_const(slot).then(|| {
+                    self.heap
+                        .borrow()
+                        .format_value(&Value::Obj(globals.name(slot)))
+                })
+            }
+            Target::Property(_) | Target::Index => None,
+        };
+        if let Some(name) = name {
+            self.error_at(
+                self.previous_token(),
+                &format!("Can't assign to constant '{}'.", name),
+            );
+        }
+    }
+
+    // Follows an upvalue of the function at `level` back to the local it
+    // captures and returns that local's name if it is a constant.
+    fn const_upvalue(&self, level: usize, index: u8) -> Option<String> {
+        let source = if level == self.enclosing.borrow().len() {
+            self.upvalues.borrow()[index as usize]
+        } else {
+            self.enclosing.borrow()[level].upvalues[index as usize]
+        };
+        if !source.is_local {
+            return self.const_upvalue(level - 1, source.index);
+        }
+        let enclosing = self.enclosing.borrow();
+        let local = &enclosing[level - 1].locals[source.index as usize];
+        local.is_const.then(|| local.token.lexeme.to_string())
+    }
+
+    // `++x` and `--x` compile their operand as a read and then turn that
+    // read into an update.
+    fn prefix_step(parser: &Parser, _can_assign: bool) {
+        let token_type = parser.previous_token().unwrap().token_type;
+        parser.parse_precedence(Precedence::Unary);
+
+        // Only a variable, property or index read right at the end of the
+        // operand can be updated.
+        let Some(target) = parser.target.take() else {
+            parser.error_at(parser.previous_token(), "Invalid assignment target.");
+            return;
+        };
+        parser.chunk.borrow_mut().pop();
+        parser.duplicate_operands(target.operands());
+        parser.emit_byte(target.get());
+        parser.emit_step(token_type);
+        parser.check_assignable(target);
+        parser.emit_byte(target.set());
+    }
+
+    // Leaves a second copy of a target's object and index on the stack, one
+    // for reading the old value and one for storing the new one.
+    fn duplicate_operands(&self, operands: u8) {
+        for _ in 0..operands {
+            self.emit_byte(OpCode::Dup(operands - 1));
+        }
+    }
+
+    fn emit_step(&self, token_type: TokenType) {
+        self.emit_constant(Value::Int(1));
+        if token_type == TokenType::PlusPlus {
+            self.emit_byte(OpCode::Add);
+        } else {
+            self.emit_byte(OpCode::Subtract);
+        }
+    }
+
+    // Compiles the arguments and returns the instruction that makes the
+    // call. With `...` anywhere in the list every argument is wrapped in a
+    // list of its own so `CallSpread` can flatten them all, and named
+    // arguments `name: value` go after the positional ones.
+    fn argument_list(&self) -> OpCode {
+        let first = self.current_token().unwrap().token_type;
+        let (spread, named) = self.scanner.argument_kinds(first);
+        if spread && named {
+            self.error_at(
+                self.current_token(),
+                "Can't combine spread and named arguments.",
+            );
+        }
+
+        let mut count: usize = 0;
+        let mut names = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if self.match_token_type(TokenType::DotDotDot) {
+                    self.expression();
+                } else if self.check(TokenType::Identifier)
+                    && self.scanner.peek_tokens(1)[0].token_type == TokenType::Colon
+                {
+                    self.advance();
+                    names.push(self.identifier_constant(&self.previous_token().unwrap()));
+                    self.advance();
+                    self.expression();
+                } else {
+                    if !names.is_empty() {
+                        self.error_at(
+                            self.current_token(),
+                            "Positional arguments can't follow named arguments.",
+                        );
+                    }
+                    self.expression();
+                    if spread {
+                        self.emit_byte(OpCode::BuildList(1));
+                    }
+                }
+                if count == u8::MAX as usize {
+                    self.error_at(self.previous_token(), "Can't have more than 255 arguments.");
+                }
+                count += 1;
+                if !self.match_token_type(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+
+        if spread {
+            return OpCode::CallSpread(count as u8);
+        }
+        if names.is_empty() {
+            return OpCode::Call(count as u8);
+        }
+        // The names are constants already, which keeps them alive while the
+        // list holding them is allocated.
+        let names: Vec<Value> = {
+            let chunk = self.chunk.borrow();
+            names
+                .iter()
+                .map(|name| chunk.constants[*name as usize])
+                .collect()
+        };
+        let list = {
+            let mut heap = self.heap.borrow_mut();
+            self.collect_garbage(&mut heap, &[]);
+            heap.alloc(Obj::List(names))
+        };
+        let list = self.make_constant(Value::Obj(list));
+        OpCode::CallNamed(count as u8, list)
+    }
 
-    fn binary(parser: &Parser) {
+    fn binary(parser: &Parser, _can_assign: bool) {
         let operator_type = parser.previous_token().unwrap().token_type;
-        if let Some(rule) = parser.get_rule(&operator_type) {
+        if operator_type == TokenType::StarStar {
+            // Right associative, and the exponent may be negated: `2 ** -1`.
+            parser.parse_precedence(Precedence::Unary);
+        } else if let Some(rule) = parser.get_rule(&operator_type) {
             if let Some(next_precedence) = rule.precedence.next() {
                 parser.parse_precedence(next_precedence);
             }
@@ -304,72 +2133,247 @@ impl<'a> Parser<'a> {
             TokenType::Slash => {
                 parser.emit_byte(OpCode::Divide);
             }
+            TokenType::SlashSlash => {
+                parser.emit_byte(OpCode::IntDivide);
+            }
+            TokenType::Percent => {
+                parser.emit_byte(OpCode::Modulo);
+            }
+            TokenType::StarStar => {
+                parser.emit_byte(OpCode::Power);
+            }
+            TokenType::Ampersand => {
+                parser.emit_byte(OpCode::BitAnd);
+            }
+            TokenType::Pipe => {
+                parser.emit_byte(OpCode::BitOr);
+            }
+            TokenType::Caret => {
+                parser.emit_byte(OpCode::BitXor);
+            }
+            TokenType::LessLess => {
+                parser.emit_byte(OpCode::ShiftLeft);
+            }
+            TokenType::GreaterGreater => {
+                parser.emit_byte(OpCode::ShiftRight);
+            }
+            TokenType::DotDot => {
+                parser.emit_byte(OpCode::Range);
+            }
             _ => {
                 // Handle unexpected cases (this should be unreachable)
-                return;
             }
         }
     }
 
     fn resolve_local(&self, token: &Token) -> Option<u8> {
-        self.locals
-            .borrow()
+        let locals = self.locals.borrow();
+        let index = locals
             .iter()
-            .rposition(|local| local.lexeme == token.lexeme)
-            .map(|pos| pos as u8)
+            .rposition(|local| local.token.lexeme == token.lexeme)?;
+
+        if locals[index].depth.is_none() {
+            self.error_at(
+                Some(token.clone()),
+                "Can't read local variable in its own initializer.",
+            );
+        }
+        Some(index as u8)
     }
 
-    fn variable(parser: &Parser) {
-        let token = parser.previous_token().expect("Expect previous token");
-        // First determine if it's a local or global variable and get the index
-        let (get_op, set_op) = {
-            if let Some(local_index) = parser.resolve_local(&token) {
-                (OpCode::GetLocal(local_index), OpCode::SetLocal(local_index))
-            } else {
-                let global_index = parser
-                    .chunk
-                    .borrow_mut()
-                    .add_constant(Value::String(token.lexeme.to_string()));
-                (
-                    OpCode::GetGlobal(global_index),
-                    OpCode::SetGlobal(global_index),
-                )
+    // Looks for the name among the locals of the enclosing functions, from
+    // the innermost outwards, and threads an upvalue through every function
+    // in between. `level` indexes `enclosing`, with its length standing for
+    // the function being compiled.
+    fn resolve_upvalue(&self, token: &Token, level: usize) -> Option<u8> {
+        let parent = level.checked_sub(1)?;
+        let local = {
+            let mut enclosing = self.enclosing.borrow_mut();
+            let locals = &mut enclosing[parent].locals;
+            let index = locals
+                .iter()
+                .rposition(|local| local.token.lexeme == token.lexeme);
+            if let Some(index) = index {
+                locals[index].is_captured = true;
             }
+            index
+        };
+        if let Some(index) = local {
+            return Some(self.add_upvalue(level, index as u8, true));
+        }
+
+        let upvalue = self.resolve_upvalue(token, parent)?;
+        Some(self.add_upvalue(level, upvalue, false))
+    }
+
+    fn add_upvalue(&self, level: usize, index: u8, is_local: bool) -> u8 {
+        let mut enclosing = self.enclosing.borrow_mut();
+        let mut current = self.upvalues.borrow_mut();
+        let upvalues = if level == enclosing.len() {
+            &mut *current
+        } else {
+            &mut enclosing[level].upvalues
         };
 
-        // Check if it's an assignment by peeking at the current token
-        if let Some(current) = parser.current_token() {
-            if current.token_type == TokenType::Equal {
-                parser.advance(); // Consume the equals sign
-                parser.expression(); // Now uses &self directly
-                parser.emit_byte(set_op);
-                return;
+        let source = UpvalueSource { is_local, index };
+        if let Some(existing) = upvalues
+            .iter()
+            .position(|upvalue| upvalue.is_local == is_local && upvalue.index == index)
+        {
+            return existing as u8;
+        }
+        if upvalues.len() > u8::MAX as usize {
+            self.error_at(
+                self.previous_token(),
+                "Too many closure variables in function.",
+            );
+            return 0;
+        }
+        upvalues.push(source);
+        (upvalues.len() - 1) as u8
+    }
+
+    // Whether the name is a local, captured or global variable.
+    fn resolve_variable(&self, token: &Token) -> Target {
+        let level = self.enclosing.borrow().len();
+        if let Some(local_index) = self.resolve_local(token) {
+            Target::Local(local_index)
+        } else if let Some(upvalue) = self.resolve_upvalue(token, level) {
+            Target::Upvalue(upvalue)
+        } else {
+            Target::Global(self.global_slot(token))
+        }
+    }
+
+    fn variable(parser: &Parser, can_assign: bool) {
+        let token = parser.previous_token().expect("Expect previous token");
+        let target = parser.resolve_variable(&token);
+
+        // If not an assignment, emit the get operation
+        if !parser.assignment(target, can_assign) {
+            parser.emit_target_get(target);
+        }
+    }
+
+    fn emit_target_get(&self, target: Target) {
+        self.emit_byte(target.get());
+        self.target.replace(Some(target));
+    }
+
+    // `this` is slot zero of the method, or captured from it, and can't be
+    // assigned.
+    fn this(parser: &Parser, _can_assign: bool) {
+        let token = parser.previous_token().expect("Expect previous token");
+        if *parser.class_depth.borrow() == 0 {
+            parser.error_at(Some(token), "Can't use 'this' outside of a class.");
+            return;
+        }
+        let target = parser.resolve_variable(&token);
+        parser.emit_byte(target.get());
+    }
+
+    fn string(parser: &Parser, _can_assign: bool) {
+        let lexeme = parser.previous_token().unwrap().lexeme;
+        let value = parser.intern(scanner::string_value(lexeme));
+
+        parser.emit_constant(Value::Obj(value));
+    }
+
+    // `"a ${x} b"` arrives as an `Interpolation` token for `"a ${`, the
+    // tokens of `x` and a closing `String` token for `} b"`. Each embedded
+    // expression is converted with `ToString` and appended to the segments
+    // before it.
+    fn interpolation(parser: &Parser, _can_assign: bool) {
+        Parser::string(parser, false);
+        loop {
+            parser.expression();
+            parser.emit_bytes(OpCode::ToString, OpCode::Add);
+
+            if !parser.match_token_type(TokenType::Interpolation) {
+                break;
+            }
+            Parser::string(parser, false);
+            parser.emit_byte(OpCode::Add);
+        }
+
+        // Without the closing segment the previous token isn't a string.
+        if !parser.match_token_type(TokenType::String) {
+            parser.error_at(
+                parser.current_token(),
+                "Expect end of string interpolation.",
+            );
+            return;
+        }
+        Parser::string(parser, false);
+        parser.emit_byte(OpCode::Add);
+    }
+
+    fn number(parser: &Parser, _can_assign: bool) {
+        let source_str = parser.previous_token().unwrap().lexeme;
+        // Literals without a fractional part are integers, which become big
+        // ints when they don't fit into an `i64`. The `n` suffix spells that
+        // out but isn't needed.
+        let value = if source_str.contains('.') {
+            Value::Number(source_str.parse::<f64>().unwrap())
+        } else {
+            let digits = source_str.strip_suffix('n').unwrap_or(source_str);
+            let int = BigInt::parse(digits, 10).unwrap();
+            match int.to_i64() {
+                Some(int) => Value::Int(int),
+                None => Value::Obj(parser.intern_big_int(int)),
             }
-        }
+        };
 
-        // If not an assignment, emit the get operation
-        parser.emit_byte(get_op);
+        parser.emit_constant(value);
     }
 
-    fn string(parser: &Parser) {}
+    fn and_(parser: &Parser, _can_assign: bool) {
+        let end_jump = parser.emit_jump(OpCode::JumpIfFalse(0));
+        parser.emit_byte(OpCode::Pop);
+        parser.parse_precedence(Precedence::And.next().unwrap());
+        parser.patch_jump(end_jump);
+    }
 
-    fn number(parser: &Parser) {
-        let source_str = parser.previous_token().unwrap().lexeme;
-        let my_int = source_str.parse::<f64>().unwrap();
+    fn or_(parser: &Parser, _can_assign: bool) {
+        let else_jump = parser.emit_jump(OpCode::JumpIfFalse(0));
+        let end_jump = parser.emit_jump(OpCode::Jump(0));
+        parser.patch_jump(else_jump);
+        parser.emit_byte(OpCode::Pop);
+        parser.parse_precedence(Precedence::Or.next().unwrap());
+        parser.patch_jump(end_jump);
+    }
 
-        let constant_index = parser
-            .chunk
-            .borrow_mut()
-            .add_constant(Value::Number(my_int));
+    // The else branch is parsed at the operator's own precedence, which
+    // makes `a ? b : c ? d : e` group as `a ? b : (c ? d : e)`.
+    fn conditional(parser: &Parser, _can_assign: bool) {
+        let else_jump = parser.emit_jump(OpCode::JumpIfFalse(0));
+        parser.emit_byte(OpCode::Pop);
+        parser.expression();
+        parser.consume(TokenType::Colon, "Expect ':' after then branch of '?'.");
+        let end_jump = parser.emit_jump(OpCode::Jump(0));
 
-        parser.emit_byte(OpCode::Constant(constant_index));
+        parser.patch_jump(else_jump);
+        parser.emit_byte(OpCode::Pop);
+        parser.parse_precedence(Precedence::Conditional);
+        parser.patch_jump(end_jump);
     }
 
-    // fn emit_constant(&self, value: Value) {}
-
-    fn and_(parser: &Parser) {}
-    fn literal(parser: &Parser) {}
-    fn or_(parser: &Parser) {}
+    // Right associative like `?:`, and the right operand only runs when
+    // the left one is nil.
+    fn coalesce(parser: &Parser, _can_assign: bool) {
+        let end_jump = parser.emit_jump(OpCode::JumpIfNotNil(0));
+        parser.emit_byte(OpCode::Pop);
+        parser.parse_precedence(Precedence::Coalesce);
+        parser.patch_jump(end_jump);
+    }
+    fn literal(parser: &Parser, _can_assign: bool) {
+        match parser.previous_token().unwrap().token_type {
+            TokenType::False => parser.emit_byte(OpCode::False),
+            TokenType::Nil => parser.emit_byte(OpCode::Nil),
+            TokenType::True => parser.emit_byte(OpCode::True),
+            _ => {}
+        }
+    }
 }
 
 pub struct Compiler<'a> {
@@ -381,18 +2385,26 @@ impl<'a> Compiler<'a> {
         Self { source }
     }
 
-    pub fn compile(&self, chunk: &mut Chunk) -> bool {
-        let parser = Parser::new(chunk, &self.source);
+    pub fn compile(
+        &self,
+        chunk: &mut Chunk,
+        heap: &mut Heap,
+        globals: &mut Globals,
+        vm_roots: &[Value],
+    ) -> bool {
+        let parser = Parser::new(chunk, self.source, heap, globals, vm_roots);
         parser.run();
         // println! {"{:#?}", chunk};
         // process::exit(0);
 
         self.end_compiler(&parser);
-        return !*parser.had_error.borrow();
+        let had_error = *parser.had_error.borrow();
+        !had_error
     }
 
+    // The top level returns its module, which sits in slot zero.
     fn end_compiler(&self, parser: &Parser) {
-        parser.emit_byte(OpCode::Return);
+        parser.emit_bytes(OpCode::GetLocal(0), OpCode::Return);
     }
 }
 
@@ -402,8 +2414,8 @@ pub static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
         TokenType::LeftParen,
         ParseRule {
             prefix: Some(Parser::grouping),
-            infix: None,
-            precedence: Precedence::None,
+            infix: Some(Parser::call),
+            precedence: Precedence::Call,
         },
     );
     map.insert(
@@ -417,7 +2429,7 @@ pub static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
     map.insert(
         TokenType::LeftBrace,
         ParseRule {
-            prefix: None,
+            prefix: Some(Parser::map),
             infix: None,
             precedence: Precedence::None,
         },
@@ -431,7 +2443,15 @@ pub static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
         },
     );
     map.insert(
-        TokenType::Comma,
+        TokenType::LeftBracket,
+        ParseRule {
+            prefix: Some(Parser::list),
+            infix: Some(Parser::subscript),
+            precedence: Precedence::Call,
+        },
+    );
+    map.insert(
+        TokenType::RightBracket,
         ParseRule {
             prefix: None,
             infix: None,
@@ -439,13 +2459,45 @@ pub static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
         },
     );
     map.insert(
-        TokenType::Dot,
+        TokenType::Colon,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::Question,
+        ParseRule {
+            prefix: None,
+            infix: Some(Parser::conditional),
+            precedence: Precedence::Conditional,
+        },
+    );
+    map.insert(
+        TokenType::QuestionQuestion,
+        ParseRule {
+            prefix: None,
+            infix: Some(Parser::coalesce),
+            precedence: Precedence::Coalesce,
+        },
+    );
+    map.insert(
+        TokenType::Comma,
         ParseRule {
             prefix: None,
             infix: None,
             precedence: Precedence::None,
         },
     );
+    map.insert(
+        TokenType::Dot,
+        ParseRule {
+            prefix: None,
+            infix: Some(Parser::dot),
+            precedence: Precedence::Call,
+        },
+    );
     map.insert(
         TokenType::Minus,
         ParseRule {
@@ -550,6 +2602,150 @@ pub static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
             precedence: Precedence::Comparison,
         },
     );
+    map.insert(
+        TokenType::DotDot,
+        ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Range,
+        },
+    );
+    map.insert(
+        TokenType::Arrow,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::Percent,
+        ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Factor,
+        },
+    );
+    map.insert(
+        TokenType::Ampersand,
+        ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::BitAnd,
+        },
+    );
+    map.insert(
+        TokenType::Pipe,
+        ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::BitOr,
+        },
+    );
+    map.insert(
+        TokenType::Caret,
+        ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::BitXor,
+        },
+    );
+    map.insert(
+        TokenType::Tilde,
+        ParseRule {
+            prefix: Some(Parser::unary),
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::LessLess,
+        ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Shift,
+        },
+    );
+    map.insert(
+        TokenType::GreaterGreater,
+        ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Shift,
+        },
+    );
+    map.insert(
+        TokenType::SlashSlash,
+        ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Factor,
+        },
+    );
+    map.insert(
+        TokenType::StarStar,
+        ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Exponent,
+        },
+    );
+    map.insert(
+        TokenType::PlusEqual,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::MinusEqual,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::StarEqual,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::SlashEqual,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::PercentEqual,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::PlusPlus,
+        ParseRule {
+            prefix: Some(Parser::prefix_step),
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::MinusMinus,
+        ParseRule {
+            prefix: Some(Parser::prefix_step),
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
     map.insert(
         TokenType::Identifier,
         ParseRule {
@@ -566,6 +2762,14 @@ pub static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
             precedence: Precedence::None,
         },
     );
+    map.insert(
+        TokenType::Interpolation,
+        ParseRule {
+            prefix: Some(Parser::interpolation),
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
     map.insert(
         TokenType::Number,
         ParseRule {
@@ -582,6 +2786,22 @@ pub static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
             precedence: Precedence::And,
         },
     );
+    map.insert(
+        TokenType::Break,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::Continue,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
     map.insert(
         TokenType::Class,
         ParseRule {
@@ -617,7 +2837,7 @@ pub static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
     map.insert(
         TokenType::Fun,
         ParseRule {
-            prefix: None,
+            prefix: Some(Parser::lambda),
             infix: None,
             precedence: Precedence::None,
         },
@@ -630,6 +2850,14 @@ pub static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
             precedence: Precedence::None,
         },
     );
+    map.insert(
+        TokenType::In,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
     map.insert(
         TokenType::Nil,
         ParseRule {
@@ -662,6 +2890,14 @@ pub static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
             precedence: Precedence::None,
         },
     );
+    map.insert(
+        TokenType::Static,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
     map.insert(
         TokenType::Super,
         ParseRule {
@@ -673,7 +2909,7 @@ pub static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
     map.insert(
         TokenType::This,
         ParseRule {
-            prefix: None,
+            prefix: Some(Parser::this),
             infix: None,
             precedence: Precedence::None,
         },
@@ -702,6 +2938,70 @@ pub static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
             precedence: Precedence::None,
         },
     );
+    map.insert(
+        TokenType::Import,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::As,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::Throw,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::Const,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::Match,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::Try,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::Catch,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
+    map.insert(
+        TokenType::Finally,
+        ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        },
+    );
     map.insert(
         TokenType::Error,
         ParseRule {
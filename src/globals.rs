@@ -0,0 +1,82 @@
+use crate::{
+    chunk::Value,
+    memory::{Heap, ObjRef},
+};
+use std::collections::HashMap;
+
+// Global variables are resolved to numbered slots while compiling, so the VM
+// indexes a vector instead of hashing names. A slot is handed out the first
+// time a name is mentioned, which lets code refer to globals declared further
+// down; its value stays `None` until the declaration has run.
+#[derive(Debug, Default)]
+pub struct Globals {
+    slots: HashMap<ObjRef, u16>,
+    names: Vec<ObjRef>,
+    values: Vec<Option<Value>>,
+    // Slots declared with `const`. They are marked while compiling, so the
+    // VM also refuses assignments compiled before the declaration or in
+    // other modules.
+    constants: Vec<bool>,
+}
+
+impl Globals {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns `None` once every slot an instruction can address is taken.
+    pub fn resolve(&mut self, name: ObjRef) -> Option<u16> {
+        if let Some(slot) = self.slots.get(&name) {
+            return Some(*slot);
+        }
+
+        let slot = u16::try_from(self.names.len()).ok()?;
+        self.slots.insert(name, slot);
+        self.names.push(name);
+        self.values.push(None);
+        self.constants.push(false);
+        Some(slot)
+    }
+
+    // Finds the slot of a name without handing out a new one.
+    pub fn lookup(&self, name: ObjRef) -> Option<u16> {
+        self.slots.get(&name).copied()
+    }
+
+    pub fn name(&self, slot: u16) -> ObjRef {
+        self.names[slot as usize]
+    }
+
+    pub fn get(&self, slot: u16) -> Option<Value> {
+        self.values[slot as usize]
+    }
+
+    pub fn is_defined(&self, slot: u16) -> bool {
+        self.values[slot as usize].is_some()
+    }
+
+    pub fn set(&mut self, slot: u16, value: Value) {
+        self.values[slot as usize] = Some(value);
+    }
+
+    pub fn is_const(&self, slot: u16) -> bool {
+        self.constants[slot as usize]
+    }
+
+    pub fn make_const(&mut self, slot: u16) {
+        self.constants[slot as usize] = true;
+    }
+
+    // The names have to survive as well: a freed name could be handed out
+    // again to a different string that would then resolve to this slot.
+    pub fn mark(&self, heap: &mut Heap) {
+        for value in self.references() {
+            heap.mark_value(&value);
+        }
+    }
+
+    pub fn references(&self) -> impl Iterator<Item = Value> + '_ {
+        let names = self.names.iter().map(|name| Value::Obj(*name));
+        names.chain(self.values.iter().flatten().copied())
+    }
+}
@@ -1,8 +1,12 @@
-use std::{env, fs, process};
+use std::{env, fs, path::Path, process};
 use vm::{InterpretResult, VM};
 
+pub mod bigint;
 pub mod chunk;
 pub mod compiler;
+pub mod globals;
+pub mod memory;
+pub mod object;
 pub mod scanner;
 pub mod token;
 pub mod vm;
@@ -10,16 +14,26 @@ pub mod vm;
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() < 2 {
-        eprintln!("Usage: {} <script>", args[0]);
+    let stress_gc = args.iter().skip(1).any(|arg| arg == "--stress-gc");
+    let debug = args.iter().skip(1).any(|arg| arg == "--debug");
+    let paths: Vec<&String> = args
+        .iter()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+
+    if paths.len() != 1 {
+        eprintln!("Usage: {} [--stress-gc] [--debug] <script>", args[0]);
         process::exit(64);
     }
 
-    run_file(&args[1]);
+    run_file(paths[0], stress_gc, debug);
 }
 
-fn run_file(path: &str) {
+fn run_file(path: &str, stress_gc: bool, debug: bool) {
     let mut vm = VM::new();
+    vm.set_stress_gc(stress_gc);
+    vm.set_debug(debug);
 
     let source = match fs::read_to_string(path) {
         Ok(content) => content,
@@ -29,7 +43,7 @@ fn run_file(path: &str) {
         }
     };
 
-    match vm.interpret(&source) {
+    match vm.interpret(Path::new(path), &source) {
         InterpretResult::Ok => {}
         InterpretResult::CompileError => {
             eprintln!("Compilation failed.");
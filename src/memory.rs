@@ -0,0 +1,332 @@
+use crate::{
+    bigint::BigInt,
+    chunk::Value,
+    object::{FunctionKind, Obj, Upvalue},
+};
+use std::collections::HashMap;
+use std::fmt;
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const INITIAL_NEXT_GC: usize = 1024 * 1024;
+
+// Handle to an object on the heap. It is only valid as long as the object is
+// reachable from a root when a collection runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+#[derive(Debug)]
+struct HeapEntry {
+    is_marked: bool,
+    size: usize,
+    obj: Obj,
+}
+
+// Interning counters shown in the VM's debug output.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InternStats {
+    pub live_strings: usize,
+    pub lookups: usize,
+    pub hits: usize,
+}
+
+impl fmt::Display for InternStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hit_rate = if self.lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 * 100.0 / self.lookups as f64
+        };
+        writeln!(f, "== string interning ==")?;
+        writeln!(f, "live strings: {}", self.live_strings)?;
+        writeln!(f, "lookups:      {}", self.lookups)?;
+        writeln!(f, "hits:         {} ({:.1}%)", self.hits, hit_rate)
+    }
+}
+
+#[derive(Debug)]
+pub struct Heap {
+    entries: Vec<Option<HeapEntry>>,
+    free_slots: Vec<usize>,
+    gray_stack: Vec<ObjRef>,
+    // Every string object on the heap, so equal strings share one handle.
+    // Entries are dropped when the string itself is swept.
+    strings: HashMap<String, ObjRef>,
+    // Big ints are interned the same way, which lets equality and map keys
+    // compare them by handle.
+    big_ints: HashMap<BigInt, ObjRef>,
+    intern_stats: InternStats,
+    bytes_allocated: usize,
+    next_gc: usize,
+    stress: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            free_slots: Vec::new(),
+            gray_stack: Vec::new(),
+            strings: HashMap::new(),
+            big_ints: HashMap::new(),
+            intern_stats: InternStats::default(),
+            bytes_allocated: 0,
+            next_gc: INITIAL_NEXT_GC,
+            stress: false,
+        }
+    }
+
+    // With stress mode on every allocation is preceded by a full collection,
+    // which shakes out objects that are not reachable from any root.
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    // Never collects by itself; callers run `collect` first, after marking
+    // their roots, whenever `should_collect` says so.
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = obj.size();
+        self.bytes_allocated += size;
+
+        let entry = Some(HeapEntry {
+            is_marked: false,
+            size,
+            obj,
+        });
+        match self.free_slots.pop() {
+            Some(slot) => {
+                self.entries[slot] = entry;
+                ObjRef(slot)
+            }
+            None => {
+                self.entries.push(entry);
+                ObjRef(self.entries.len() - 1)
+            }
+        }
+    }
+
+    pub fn find_string(&mut self, string: &str) -> Option<ObjRef> {
+        self.intern_stats.lookups += 1;
+        let handle = self.strings.get(string).copied();
+        if handle.is_some() {
+            self.intern_stats.hits += 1;
+        }
+        handle
+    }
+
+    // Like `alloc`, callers check `find_string` and collect beforehand.
+    pub fn intern(&mut self, string: String) -> ObjRef {
+        let handle = self.alloc(Obj::String(string.clone()));
+        self.strings.insert(string, handle);
+        handle
+    }
+
+    pub fn find_big_int(&self, int: &BigInt) -> Option<ObjRef> {
+        self.big_ints.get(int).copied()
+    }
+
+    // Like `intern`, callers check `find_big_int` and collect beforehand.
+    pub fn intern_big_int(&mut self, int: BigInt) -> ObjRef {
+        let handle = self.alloc(Obj::BigInt(int.clone()));
+        self.big_ints.insert(int, handle);
+        handle
+    }
+
+    pub fn intern_stats(&self) -> InternStats {
+        InternStats {
+            live_strings: self.strings.len(),
+            ..self.intern_stats
+        }
+    }
+
+    pub fn get(&self, handle: ObjRef) -> &Obj {
+        match &self.entries[handle.0] {
+            Some(entry) => &entry.obj,
+            None => panic!("Use of freed object {:?}", handle),
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: ObjRef) -> &mut Obj {
+        match &mut self.entries[handle.0] {
+            Some(entry) => &mut entry.obj,
+            None => panic!("Use of freed object {:?}", handle),
+        }
+    }
+
+    pub fn as_str(&self, value: &Value) -> Option<&str> {
+        match value {
+            Value::Obj(handle) => match self.get(*handle) {
+                Obj::String(string) => Some(string),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Runtime string conversion used by `print` and string interpolation.
+    pub fn format_value(&self, value: &Value) -> String {
+        match value {
+            Value::Int(int) => int.to_string(),
+            // Floats always show a fractional part so they can be told apart
+            // from ints.
+            Value::Number(number) if number.is_finite() && number.fract() == 0.0 => {
+                format!("{:.1}", number)
+            }
+            Value::Number(number) => number.to_string(),
+            Value::Boolean(boolean) => boolean.to_string(),
+            Value::Nil => "nil".to_string(),
+            Value::Obj(handle) => match self.get(*handle) {
+                Obj::String(string) => string.clone(),
+                Obj::BigInt(int) => int.to_string(),
+                Obj::List(items) => {
+                    let items: Vec<String> =
+                        items.iter().map(|item| self.format_value(item)).collect();
+                    format!("[{}]", items.join(", "))
+                }
+                Obj::Map(map) => {
+                    let entries: Vec<String> = map
+                        .iter()
+                        .map(|(key, value)| {
+                            let key = self.format_value(&Value::from(*key));
+                            format!("{}: {}", key, self.format_value(value))
+                        })
+                        .collect();
+                    format!("{{{}}}", entries.join(", "))
+                }
+                Obj::Range(start, end) => format!("{}..{}", start, end),
+                Obj::Iterator(_) => "<iterator>".to_string(),
+                Obj::Error(error) => error.message.clone(),
+                Obj::Function(function) => match (function.kind, &function.name) {
+                    (FunctionKind::Script, _) => "<script>".to_string(),
+                    (_, Some(name)) => format!("<fn {}>", name),
+                    (_, None) => "<fn>".to_string(),
+                },
+                Obj::Closure(closure) => self.format_value(&Value::Obj(closure.function)),
+                Obj::Upvalue(_) => "<upvalue>".to_string(),
+                Obj::Module(module) => format!("<module {}>", module.path.display()),
+                Obj::Class(class) => {
+                    format!("<class {}>", self.format_value(&Value::Obj(class.name)))
+                }
+                Obj::Instance(instance) => match self.get(instance.class) {
+                    Obj::Class(class) => {
+                        format!("<{} instance>", self.format_value(&Value::Obj(class.name)))
+                    }
+                    _ => unreachable!("Expected a class"),
+                },
+                Obj::BoundMethod(bound) => self.format_value(&Value::Obj(bound.method)),
+            },
+        }
+    }
+
+    pub fn mark_value(&mut self, value: &Value) {
+        if let Value::Obj(handle) = value {
+            self.mark_object(*handle);
+        }
+    }
+
+    pub fn mark_object(&mut self, handle: ObjRef) {
+        if let Some(entry) = &mut self.entries[handle.0] {
+            if entry.is_marked {
+                return;
+            }
+            entry.is_marked = true;
+            self.gray_stack.push(handle);
+        }
+    }
+
+    // Expects every root to be marked already.
+    pub fn collect(&mut self) {
+        self.trace_references();
+        self.sweep();
+        self.next_gc = self.bytes_allocated.max(INITIAL_NEXT_GC) * GC_HEAP_GROW_FACTOR;
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(handle) = self.gray_stack.pop() {
+            self.blacken_object(handle);
+        }
+    }
+
+    fn blacken_object(&mut self, handle: ObjRef) {
+        let children: Vec<Value> = match self.get(handle) {
+            Obj::String(_) | Obj::BigInt(_) | Obj::Range(..) | Obj::Error(_) => return,
+            Obj::Iterator(iterator) => vec![Value::Obj(iterator.iterable)],
+            Obj::List(items) => items.clone(),
+            Obj::Map(map) => map
+                .iter()
+                .flat_map(|(key, value)| [Value::from(*key), *value])
+                .collect(),
+            Obj::Function(function) => function.chunk.constants.clone(),
+            Obj::Closure(closure) => {
+                let mut children = vec![Value::Obj(closure.function), Value::Obj(closure.module)];
+                children.extend(closure.upvalues.iter().map(|upvalue| Value::Obj(*upvalue)));
+                children
+            }
+            Obj::Upvalue(Upvalue::Closed(value)) => vec![*value],
+            Obj::Upvalue(Upvalue::Open(_)) => return,
+            Obj::Module(module) => module.globals.references().collect(),
+            Obj::Class(class) => {
+                let mut children = vec![Value::Obj(class.name)];
+                children.extend(class.closures().map(Value::Obj));
+                // The names of the members stay alive with the class.
+                children.extend(
+                    [
+                        &class.methods,
+                        &class.static_methods,
+                        &class.getters,
+                        &class.setters,
+                    ]
+                    .into_iter()
+                    .flat_map(|members| members.keys().map(|name| Value::Obj(*name))),
+                );
+                children
+            }
+            Obj::Instance(instance) => {
+                let mut children = vec![Value::Obj(instance.class)];
+                children.extend(
+                    instance
+                        .fields
+                        .iter()
+                        .flat_map(|(name, value)| [Value::Obj(*name), *value]),
+                );
+                children
+            }
+            Obj::BoundMethod(bound) => vec![bound.receiver, Value::Obj(bound.method)],
+        };
+        for child in &children {
+            self.mark_value(child);
+        }
+    }
+
+    fn sweep(&mut self) {
+        for (slot, entry) in self.entries.iter_mut().enumerate() {
+            match entry {
+                Some(live) if live.is_marked => live.is_marked = false,
+                Some(dead) => {
+                    match &dead.obj {
+                        Obj::String(string) => {
+                            self.strings.remove(string);
+                        }
+                        Obj::BigInt(int) => {
+                            self.big_ints.remove(int);
+                        }
+                        _ => {}
+                    }
+                    self.bytes_allocated -= dead.size;
+                    *entry = None;
+                    self.free_slots.push(slot);
+                }
+                None => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use crate::{
+    bigint::BigInt,
+    chunk::{Chunk, OpCode, Value},
+    globals::Globals,
+    memory::ObjRef,
+};
+use std::collections::HashMap;
+use std::mem;
+use std::path::PathBuf;
+
+// Everything that lives on the garbage collected heap. Values only ever hold
+// an `ObjRef` handle to one of these.
+#[derive(Debug)]
+pub enum Obj {
+    String(String),
+    // Only ints outside the range of `i64`; smaller results are always
+    // turned back into `Value::Int`.
+    BigInt(BigInt),
+    List(Vec<Value>),
+    Map(LoxMap),
+    // `start..end`, counting up in steps of one and excluding `end`.
+    Range(i64, i64),
+    Iterator(LoxIterator),
+    Error(LoxError),
+    Function(LoxFunction),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Module(Module),
+    Class(LoxClass),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+}
+
+impl Obj {
+    // Rough number of bytes owned by the object, used to pace collections.
+    pub fn size(&self) -> usize {
+        mem::size_of::<Obj>()
+            + match self {
+                Obj::String(string) => string.capacity(),
+                Obj::BigInt(int) => int.size(),
+                Obj::List(items) => items.capacity() * mem::size_of::<Value>(),
+                Obj::Map(map) => map.len() * 2 * mem::size_of::<(MapKey, Value)>(),
+                Obj::Range(..) | Obj::Iterator(_) => 0,
+                Obj::Error(error) => error.message.capacity(),
+                Obj::Function(function) => {
+                    function.chunk.code.capacity() * mem::size_of::<OpCode>()
+                        + function.chunk.constants.capacity() * mem::size_of::<Value>()
+                }
+                Obj::Closure(closure) => closure.upvalues.capacity() * mem::size_of::<ObjRef>(),
+                Obj::Upvalue(_) => 0,
+                Obj::Module(module) => module.path.capacity(),
+                Obj::Class(class) => class.size(),
+                Obj::Instance(instance) => {
+                    instance.fields.capacity() * mem::size_of::<(ObjRef, Value)>()
+                }
+                Obj::BoundMethod(_) => 0,
+            }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FunctionKind {
+    // The top level of a module.
+    Script,
+    Function,
+    // Methods get their receiver as `this` in slot zero.
+    Method,
+    // Returns `this` instead of nil.
+    Initializer,
+    // Has no parameter list and runs whenever its property is read.
+    Getter,
+    // Returns its argument, so an assignment through it yields the value
+    // being assigned.
+    Setter,
+}
+
+// Compiled code. Anonymous functions have no name.
+#[derive(Debug)]
+pub struct LoxFunction {
+    pub kind: FunctionKind,
+    pub name: Option<String>,
+    // Positional parameters, the last `optional` of which have a default
+    // value. A variadic function has one more parameter after them that
+    // collects any further arguments into a list.
+    pub arity: u8,
+    pub optional: u8,
+    pub variadic: bool,
+    // Names of the positional parameters, for named arguments.
+    pub parameters: Vec<String>,
+    pub upvalues: Vec<UpvalueSource>,
+    pub chunk: Chunk,
+}
+
+impl LoxFunction {
+    // Stack slots taken by the arguments once they are bound.
+    pub fn slot_count(&self) -> usize {
+        self.arity as usize + self.variadic as usize
+    }
+
+    pub fn arity_error(&self, arg_count: usize) -> String {
+        let required = self.arity - self.optional;
+        if self.variadic {
+            format!(
+                "Expected at least {} arguments but got {}.",
+                required, arg_count
+            )
+        } else if self.optional > 0 {
+            format!(
+                "Expected {} to {} arguments but got {}.",
+                required, self.arity, arg_count
+            )
+        } else {
+            format!("Expected {} arguments but got {}.", self.arity, arg_count)
+        }
+    }
+}
+
+// Where a closure finds a captured variable when it is created: a local of
+// the enclosing function or one of the enclosing closure's upvalues.
+#[derive(Debug, Clone, Copy)]
+pub struct UpvalueSource {
+    pub is_local: bool,
+    pub index: u8,
+}
+
+// A function bound to the module whose globals it uses and to the variables
+// it captured.
+#[derive(Debug)]
+pub struct Closure {
+    pub function: ObjRef,
+    pub module: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+// A captured variable stays on the stack while its scope is active and
+// moves into the upvalue once the scope ends.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+// Members are keyed by their interned names. Static methods are called on
+// the class itself, getters and setters run when their property is read or
+// assigned on an instance.
+#[derive(Debug)]
+pub struct LoxClass {
+    pub name: ObjRef,
+    pub methods: HashMap<ObjRef, ObjRef>,
+    pub static_methods: HashMap<ObjRef, ObjRef>,
+    pub getters: HashMap<ObjRef, ObjRef>,
+    pub setters: HashMap<ObjRef, ObjRef>,
+    // The `init` method, run on every new instance.
+    pub initializer: Option<ObjRef>,
+}
+
+impl LoxClass {
+    pub fn new(name: ObjRef) -> Self {
+        LoxClass {
+            name,
+            methods: HashMap::new(),
+            static_methods: HashMap::new(),
+            getters: HashMap::new(),
+            setters: HashMap::new(),
+            initializer: None,
+        }
+    }
+
+    fn size(&self) -> usize {
+        (self.methods.capacity()
+            + self.static_methods.capacity()
+            + self.getters.capacity()
+            + self.setters.capacity())
+            * mem::size_of::<(ObjRef, ObjRef)>()
+    }
+
+    // Every closure the class refers to.
+    pub fn closures(&self) -> impl Iterator<Item = ObjRef> + '_ {
+        self.methods
+            .values()
+            .chain(self.static_methods.values())
+            .chain(self.getters.values())
+            .chain(self.setters.values())
+            .copied()
+    }
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: ObjRef,
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+// A method read as a property, which remembers the receiver it was read
+// from. The receiver of a static method is its class.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+// A source file with its own globals, which importers read as fields.
+#[derive(Debug)]
+pub struct Module {
+    pub path: PathBuf,
+    pub globals: Globals,
+    // False while the top level is still running, which is how an import
+    // cycle is recognized.
+    pub loaded: bool,
+}
+
+// What a failing built-in operation throws. Scripts can read `message` and
+// `line` from the value bound by `catch`.
+#[derive(Debug)]
+pub struct LoxError {
+    pub message: String,
+    pub line: usize,
+}
+
+// State of a `for` loop over a list, map, string or range. The position is
+// an element index, except for strings where it is a byte offset.
+#[derive(Debug)]
+pub struct LoxIterator {
+    pub iterable: ObjRef,
+    pub position: usize,
+}
+
+// The subset of values that can be used as map keys. Floats with an integral
+// value share the key of the equal int or big int, other floats compare by
+// their bits, strings and big ints by their interned handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Int(i64),
+    Number(u64),
+    Boolean(bool),
+    Nil,
+    String(ObjRef),
+    BigInt(ObjRef),
+}
+
+impl MapKey {
+    // Integral floats beyond the range of ints are left to `VM::map_key`,
+    // which has the interned big ints.
+    pub fn number(number: f64) -> Self {
+        if number.fract() == 0.0 && number >= i64::MIN as f64 && number < i64::MAX as f64 {
+            return MapKey::Int(number as i64);
+        }
+        MapKey::Number(number.to_bits())
+    }
+}
+
+impl From<MapKey> for Value {
+    fn from(key: MapKey) -> Self {
+        match key {
+            MapKey::Int(int) => Value::Int(int),
+            MapKey::Number(bits) => Value::Number(f64::from_bits(bits)),
+            MapKey::Boolean(boolean) => Value::Boolean(boolean),
+            MapKey::Nil => Value::Nil,
+            MapKey::String(handle) | MapKey::BigInt(handle) => Value::Obj(handle),
+        }
+    }
+}
+
+// Hash map that remembers insertion order, so iterating over keys or values
+// always visits entries in the order they were first added.
+#[derive(Debug, Default)]
+pub struct LoxMap {
+    entries: Vec<(MapKey, Value)>,
+    indices: HashMap<MapKey, usize>,
+}
+
+impl LoxMap {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<Value> {
+        self.indices.get(key).map(|index| self.entries[*index].1)
+    }
+
+    pub fn contains_key(&self, key: &MapKey) -> bool {
+        self.indices.contains_key(key)
+    }
+
+    // Overwriting an existing key keeps its original position.
+    pub fn insert(&mut self, key: MapKey, value: Value) {
+        match self.indices.get(&key) {
+            Some(index) => self.entries[*index].1 = value,
+            None => {
+                self.indices.insert(key, self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &MapKey) -> Option<Value> {
+        let index = self.indices.remove(key)?;
+        let (_, value) = self.entries.remove(index);
+        for (key, _) in &self.entries[index..] {
+            if let Some(later) = self.indices.get_mut(key) {
+                *later -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(MapKey, Value)> {
+        self.entries.iter()
+    }
+}
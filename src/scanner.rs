@@ -6,6 +6,9 @@ pub struct Scanner<'a> {
     start: RefCell<usize>,
     current: RefCell<usize>,
     line: RefCell<usize>,
+    // One entry per unfinished `${`, counting the braces opened inside it so
+    // the scanner knows which `}` resumes the surrounding string.
+    interpolation: RefCell<Vec<usize>>,
 }
 
 impl<'s> Scanner<'s> {
@@ -15,6 +18,7 @@ impl<'s> Scanner<'s> {
             start: RefCell::new(0),
             current: RefCell::new(0),
             line: RefCell::new(1),
+            interpolation: RefCell::new(Vec::new()),
         }
     }
 
@@ -30,73 +34,283 @@ impl<'s> Scanner<'s> {
         *self.start.borrow_mut() = self.current_index();
 
         match self.advance() {
-            '(' => return self.make_token(TokenType::LeftParen),
-            ')' => return self.make_token(TokenType::RightParen),
-            '{' => return self.make_token(TokenType::LeftBrace),
-            '}' => return self.make_token(TokenType::RightBrace),
-            ';' => return self.make_token(TokenType::Semicolon),
-            ',' => return self.make_token(TokenType::Comma),
-            '.' => return self.make_token(TokenType::Dot),
-            '-' => return self.make_token(TokenType::Minus),
-            '+' => return self.make_token(TokenType::Plus),
-            '/' => return self.make_token(TokenType::Slash),
-            '*' => return self.make_token(TokenType::Star),
+            '(' => self.make_token(TokenType::LeftParen),
+            ')' => self.make_token(TokenType::RightParen),
+            '{' => {
+                if let Some(depth) = self.interpolation.borrow_mut().last_mut() {
+                    *depth += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            }
+            '}' => {
+                let mut interpolation = self.interpolation.borrow_mut();
+                match interpolation.last_mut() {
+                    Some(0) => {
+                        interpolation.pop();
+                        drop(interpolation);
+                        self.string_segment()
+                    }
+                    Some(depth) => {
+                        *depth -= 1;
+                        self.make_token(TokenType::RightBrace)
+                    }
+                    None => self.make_token(TokenType::RightBrace),
+                }
+            }
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
+            ';' => self.make_token(TokenType::Semicolon),
+            ':' => self.make_token(TokenType::Colon),
+            '?' => {
+                if self.match_char('?') {
+                    self.make_token(TokenType::QuestionQuestion)
+                } else {
+                    self.make_token(TokenType::Question)
+                }
+            }
+            ',' => self.make_token(TokenType::Comma),
+            '.' => {
+                if self.match_char('.') {
+                    if self.match_char('.') {
+                        return self.make_token(TokenType::DotDotDot);
+                    }
+                    self.make_token(TokenType::DotDot)
+                } else {
+                    self.make_token(TokenType::Dot)
+                }
+            }
+            '-' => {
+                if self.match_char('-') {
+                    self.make_token(TokenType::MinusMinus)
+                } else if self.match_char('=') {
+                    self.make_token(TokenType::MinusEqual)
+                } else {
+                    self.make_token(TokenType::Minus)
+                }
+            }
+            '+' => {
+                if self.match_char('+') {
+                    self.make_token(TokenType::PlusPlus)
+                } else if self.match_char('=') {
+                    self.make_token(TokenType::PlusEqual)
+                } else {
+                    self.make_token(TokenType::Plus)
+                }
+            }
+            '/' => {
+                if self.match_char('/') {
+                    self.make_token(TokenType::SlashSlash)
+                } else if self.match_char('=') {
+                    self.make_token(TokenType::SlashEqual)
+                } else {
+                    self.make_token(TokenType::Slash)
+                }
+            }
+            '*' => {
+                if self.match_char('*') {
+                    self.make_token(TokenType::StarStar)
+                } else if self.match_char('=') {
+                    self.make_token(TokenType::StarEqual)
+                } else {
+                    self.make_token(TokenType::Star)
+                }
+            }
+            '%' => {
+                if self.match_char('=') {
+                    self.make_token(TokenType::PercentEqual)
+                } else {
+                    self.make_token(TokenType::Percent)
+                }
+            }
             '!' => {
                 if self.match_char('=') {
-                    return self.make_token(TokenType::BangEqual);
+                    self.make_token(TokenType::BangEqual)
                 } else {
-                    return self.make_token(TokenType::Bang);
+                    self.make_token(TokenType::Bang)
                 }
             }
             '=' => {
                 if self.match_char('=') {
-                    return self.make_token(TokenType::EqualEqual);
+                    self.make_token(TokenType::EqualEqual)
+                } else if self.match_char('>') {
+                    self.make_token(TokenType::Arrow)
                 } else {
-                    return self.make_token(TokenType::Equal);
+                    self.make_token(TokenType::Equal)
                 }
             }
             '<' => {
-                if self.match_char('=') {
-                    return self.make_token(TokenType::LessEqual);
+                if self.match_char('<') {
+                    self.make_token(TokenType::LessLess)
+                } else if self.match_char('=') {
+                    self.make_token(TokenType::LessEqual)
                 } else {
-                    return self.make_token(TokenType::Less);
+                    self.make_token(TokenType::Less)
                 }
             }
             '>' => {
-                if self.match_char('=') {
-                    return self.make_token(TokenType::GreaterEqual);
+                if self.match_char('>') {
+                    self.make_token(TokenType::GreaterGreater)
+                } else if self.match_char('=') {
+                    self.make_token(TokenType::GreaterEqual)
                 } else {
-                    return self.make_token(TokenType::Greater);
+                    self.make_token(TokenType::Greater)
                 }
             }
-            '"' => return self.string(),
-            '\0' => return self.make_token(TokenType::Eof),
-            //_ if self.is_at_end() => return self.make_token(TokenType::Eof),
-            c if c.is_alphabetic() => return self.identifier(),
-            c if c.is_digit(10) => return self.number(),
-            _ => return self.error_token("Unexpected character."),
+            '&' => self.make_token(TokenType::Ampersand),
+            '|' => self.make_token(TokenType::Pipe),
+            '^' => self.make_token(TokenType::Caret),
+            '~' => self.make_token(TokenType::Tilde),
+            '"' => self.string(false),
+            'r' if self.peek() == Some('"') => {
+                self.advance();
+                self.string(true)
+            }
+            '\0' => self.make_token(TokenType::Eof),
+            //_ if self.is_at_end() => self.make_token(TokenType::Eof),
+            c if c.is_alphabetic() || c == '_' => self.identifier(),
+            c if c.is_ascii_digit() => self.number(),
+            _ => self.error_token("Unexpected character."),
         }
     }
 
+    // Scans the next `count` tokens without consuming them, for the few
+    // places where the parser needs more than one token of lookahead.
+    pub fn peek_tokens(&self, count: usize) -> Vec<Token<'s>> {
+        self.lookahead(|| (0..count).map(|_| self.scan_token()).collect())
+    }
+
+    // Called right after the `{` of a `try` block. Skips the block and any
+    // `catch` clauses and reports whether a `catch` and whether a `finally`
+    // clause follow.
+    pub fn try_clauses(&self) -> (bool, bool) {
+        self.lookahead(|| {
+            let mut depth = 1;
+            let mut has_catch = false;
+            loop {
+                match self.scan_token().token_type {
+                    TokenType::LeftBrace => depth += 1,
+                    TokenType::RightBrace => {
+                        depth -= 1;
+                        if depth == 0 {
+                            match self.scan_token().token_type {
+                                TokenType::Catch => has_catch = true,
+                                TokenType::Finally => return (has_catch, true),
+                                _ => return (has_catch, false),
+                            }
+                        }
+                    }
+                    TokenType::Eof => return (has_catch, false),
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    // Called with the first token inside parentheses already scanned, whose
+    // type is passed in. Reports whether the matching `)` is followed by a
+    // `=>`, which makes the parentheses the parameter list of an arrow
+    // function rather than a grouping.
+    pub fn closes_parameter_list(&self, first: TokenType) -> bool {
+        self.lookahead(|| {
+            let mut depth = 0;
+            let mut token_type = first;
+            loop {
+                match token_type {
+                    TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => {
+                        depth += 1;
+                    }
+                    TokenType::RightParen if depth == 0 => {
+                        return self.scan_token().token_type == TokenType::Arrow;
+                    }
+                    TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => {
+                        if depth == 0 {
+                            return false;
+                        }
+                        depth -= 1;
+                    }
+                    TokenType::Eof => return false,
+                    _ => {}
+                }
+                token_type = self.scan_token().token_type;
+            }
+        })
+    }
+
+    // Called with the first token of an argument list already scanned, whose
+    // type is passed in. Reports whether any argument up to the closing `)`
+    // is spread with `...` and whether any is named with `name:`.
+    pub fn argument_kinds(&self, first: TokenType) -> (bool, bool) {
+        self.lookahead(|| {
+            let (mut spread, mut named) = (false, false);
+            let mut depth = 0;
+            let mut token_type = first;
+            let mut starts_argument = true;
+            loop {
+                match token_type {
+                    TokenType::DotDotDot if depth == 0 && starts_argument => spread = true,
+                    TokenType::Identifier if depth == 0 && starts_argument => {
+                        token_type = self.scan_token().token_type;
+                        named |= token_type == TokenType::Colon;
+                        starts_argument = false;
+                        continue;
+                    }
+                    TokenType::LeftParen | TokenType::LeftBracket | TokenType::LeftBrace => {
+                        depth += 1;
+                    }
+                    TokenType::RightParen | TokenType::RightBracket | TokenType::RightBrace => {
+                        if depth == 0 {
+                            return (spread, named);
+                        }
+                        depth -= 1;
+                    }
+                    TokenType::Eof => return (spread, named),
+                    _ => {}
+                }
+                starts_argument = depth == 0 && token_type == TokenType::Comma;
+                token_type = self.scan_token().token_type;
+            }
+        })
+    }
+
+    // Runs `scan` and rewinds the scanner afterwards, so the same tokens are
+    // produced again by later calls to `scan_token`.
+    fn lookahead<T>(&self, scan: impl FnOnce() -> T) -> T {
+        let start = self.start_index();
+        let current = self.current_index();
+        let line = *self.line.borrow();
+        let interpolation = self.interpolation.borrow().clone();
+
+        let result = scan();
+
+        *self.start.borrow_mut() = start;
+        *self.current.borrow_mut() = current;
+        *self.line.borrow_mut() = line;
+        *self.interpolation.borrow_mut() = interpolation;
+        result
+    }
+
     fn number(&self) -> Token<'s> {
         while let Some(c) = self.peek() {
-            if c.is_digit(10) {
+            if c.is_ascii_digit() {
                 self.advance();
             } else {
                 break;
             }
         }
-        if self.peek() == Some('.') && self.peek_next().unwrap().is_digit(10) {
+        if self.peek() == Some('.') && self.peek_next().is_some_and(|c| c.is_ascii_digit()) {
             self.advance();
             while let Some(c) = self.peek() {
-                if c.is_digit(10) {
+                if c.is_ascii_digit() {
                     self.advance();
                 } else {
                     break;
                 }
             }
+        } else if self.peek() == Some('n') {
+            // Big int suffix.
+            self.advance();
         }
-        return self.make_token(TokenType::Number);
+        self.make_token(TokenType::Number)
     }
 
     fn peek_next(&self) -> Option<char> {
@@ -109,36 +323,65 @@ impl<'s> Scanner<'s> {
 
     fn identifier(&self) -> Token<'s> {
         while let Some(c) = self.peek() {
-            if c.is_alphabetic() || c.is_digit(10) {
+            if c.is_alphabetic() || c.is_ascii_digit() || c == '_' {
                 self.advance();
             } else {
                 break;
             }
         }
-        return self.make_token(self.identifier_type());
+        self.make_token(self.identifier_type())
     }
 
     fn identifier_type(&self) -> TokenType {
         match self.source[self.start_index()..].chars().next().unwrap() {
-            'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
+            'a' => match self.check_keyword(1, 2, "nd", TokenType::And) {
+                TokenType::Identifier => self.check_keyword(1, 1, "s", TokenType::As),
+                keyword => keyword,
+            },
+            'b' => self.check_keyword(1, 4, "reak", TokenType::Break),
+            'c' => {
+                if self
+                    .source
+                    .get(self.start_index() + 1..self.start_index() + 2)
+                    == Some("l")
+                {
+                    self.check_keyword(2, 3, "ass", TokenType::Class)
+                } else if self
+                    .source
+                    .get(self.start_index() + 1..self.start_index() + 2)
+                    == Some("a")
+                {
+                    self.check_keyword(2, 3, "tch", TokenType::Catch)
+                } else {
+                    match self.check_keyword(1, 7, "ontinue", TokenType::Continue) {
+                        TokenType::Identifier => self.check_keyword(1, 4, "onst", TokenType::Const),
+                        keyword => keyword,
+                    }
+                }
+            }
             'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
             'f' => {
                 if self
                     .source
-                    .get(self.current_index() + 1..self.current_index() + 2)
+                    .get(self.start_index() + 1..self.start_index() + 2)
                     == Some("a")
                 {
                     self.check_keyword(2, 3, "lse", TokenType::False)
                 } else if self
                     .source
-                    .get(self.current_index() + 1..self.current_index() + 2)
+                    .get(self.start_index() + 1..self.start_index() + 2)
+                    == Some("i")
+                {
+                    self.check_keyword(2, 5, "nally", TokenType::Finally)
+                } else if self
+                    .source
+                    .get(self.start_index() + 1..self.start_index() + 2)
                     == Some("o")
                 {
                     self.check_keyword(2, 1, "r", TokenType::For)
                 } else if self
                     .source
-                    .get(self.current_index() + 1..self.current_index() + 2)
+                    .get(self.start_index() + 1..self.start_index() + 2)
                     == Some("u")
                 {
                     self.check_keyword(2, 1, "n", TokenType::Fun)
@@ -146,25 +389,51 @@ impl<'s> Scanner<'s> {
                     TokenType::Identifier
                 }
             }
-            'i' => self.check_keyword(1, 1, "f", TokenType::If),
+            'i' => {
+                if self
+                    .source
+                    .get(self.start_index() + 1..self.start_index() + 2)
+                    == Some("f")
+                {
+                    self.check_keyword(1, 1, "f", TokenType::If)
+                } else if self
+                    .source
+                    .get(self.start_index() + 1..self.start_index() + 2)
+                    == Some("m")
+                {
+                    self.check_keyword(2, 4, "port", TokenType::Import)
+                } else {
+                    self.check_keyword(1, 1, "n", TokenType::In)
+                }
+            }
+            'm' => self.check_keyword(1, 4, "atch", TokenType::Match),
             'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
             'o' => self.check_keyword(1, 1, "r", TokenType::Or),
             'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
             'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
-            's' => self.check_keyword(1, 4, "uper", TokenType::Super),
+            's' => match self.check_keyword(1, 4, "uper", TokenType::Super) {
+                TokenType::Identifier => self.check_keyword(1, 5, "tatic", TokenType::Static),
+                keyword => keyword,
+            },
             't' => {
                 if self
                     .source
-                    .get(self.current_index() + 1..self.current_index() + 2)
+                    .get(self.start_index() + 1..self.start_index() + 2)
                     == Some("h")
                 {
-                    self.check_keyword(2, 2, "is", TokenType::This)
+                    match self.check_keyword(2, 2, "is", TokenType::This) {
+                        TokenType::Identifier => self.check_keyword(2, 3, "row", TokenType::Throw),
+                        keyword => keyword,
+                    }
                 } else if self
                     .source
-                    .get(self.current_index() + 1..self.current_index() + 2)
+                    .get(self.start_index() + 1..self.start_index() + 2)
                     == Some("r")
                 {
-                    self.check_keyword(2, 2, "ue", TokenType::True)
+                    match self.check_keyword(2, 2, "ue", TokenType::True) {
+                        TokenType::Identifier => self.check_keyword(2, 1, "y", TokenType::Try),
+                        keyword => keyword,
+                    }
                 } else {
                     TokenType::Identifier
                 }
@@ -182,10 +451,11 @@ impl<'s> Scanner<'s> {
         suffix: &str,
         token_type: TokenType,
     ) -> TokenType {
-        if self
-            .source
-            .get(self.start_index() + start..self.current_index())
-            == Some(suffix)
+        if self.current_index() - self.start_index() == start + len
+            && self
+                .source
+                .get(self.start_index() + start..self.current_index())
+                == Some(suffix)
         {
             token_type
         } else {
@@ -193,16 +463,99 @@ impl<'s> Scanner<'s> {
         }
     }
 
-    fn string(&self) -> Token<'s> {
+    // Called with the opening quote (and the `r` prefix of raw strings)
+    // already consumed. A second pair of quotes starts a `"""` block string.
+    fn string(&self, raw: bool) -> Token<'s> {
+        if self.source[self.current_index()..].starts_with("\"\"") {
+            self.advance();
+            self.advance();
+            return self.block_string(raw);
+        }
+        if !raw {
+            return self.string_segment();
+        }
+
         loop {
-            match self.current_char() {
+            match self.advance() {
                 '"' => return self.make_token(TokenType::String),
-                '\0' => return self.error_token("Unterminated string."),
-                _ => continue,
+                '\0' if self.is_at_end() => return self.error_token("Unterminated string."),
+                '\n' => *self.line.borrow_mut() += 1,
+                _ => {}
             }
         }
     }
 
+    // Scans a regular string up to its closing quote, or up to the next `${`
+    // in which case an `Interpolation` token is produced and the rest of the
+    // string is picked up again by the `}` that closes the expression.
+    fn string_segment(&self) -> Token<'s> {
+        let mut error = None;
+        loop {
+            match self.advance() {
+                '"' => break,
+                '\0' if self.is_at_end() => return self.error_token("Unterminated string."),
+                '\n' => *self.line.borrow_mut() += 1,
+                '\\' => {
+                    if let Some(message) = self.escape() {
+                        error.get_or_insert(message);
+                    }
+                }
+                '$' if self.match_char('{') => {
+                    self.interpolation.borrow_mut().push(0);
+                    return match error {
+                        Some(message) => self.error_token(message),
+                        None => self.make_token(TokenType::Interpolation),
+                    };
+                }
+                _ => {}
+            }
+        }
+
+        match error {
+            Some(message) => self.error_token(message),
+            None => self.make_token(TokenType::String),
+        }
+    }
+
+    fn block_string(&self, raw: bool) -> Token<'s> {
+        let mut error = None;
+        loop {
+            if self.source[self.current_index()..].starts_with("\"\"\"") {
+                self.advance();
+                self.advance();
+                self.advance();
+                break;
+            }
+            match self.advance() {
+                '\0' if self.is_at_end() => return self.error_token("Unterminated string."),
+                '\n' => *self.line.borrow_mut() += 1,
+                '\\' if !raw => {
+                    if let Some(message) = self.escape() {
+                        error.get_or_insert(message);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        match error {
+            Some(message) => self.error_token(message),
+            None => self.make_token(TokenType::String),
+        }
+    }
+
+    // Validates the character following a backslash. The actual translation
+    // happens in `string_value` once the compiler needs the contents.
+    fn escape(&self) -> Option<&'s str> {
+        match self.peek() {
+            Some('n' | 't' | 'r' | '0' | '\\' | '"' | '$') => {
+                self.advance();
+                None
+            }
+            _ => Some("Invalid escape sequence."),
+        }
+    }
+
     fn error_token(&self, message: &'s str) -> Token<'s> {
         Token {
             token_type: TokenType::Error,
@@ -212,7 +565,7 @@ impl<'s> Scanner<'s> {
     }
 
     fn is_at_end(&self) -> bool {
-        self.current_char() == '\0'
+        self.peek().is_none()
     }
 
     fn current_char(&self) -> char {
@@ -227,7 +580,7 @@ impl<'s> Scanner<'s> {
             return false;
         }
         self.advance();
-        return true;
+        true
     }
 
     fn lexeme(&self) -> &'s str {
@@ -238,25 +591,22 @@ impl<'s> Scanner<'s> {
         Token {
             token_type,
             line: *self.line.borrow(),
-            lexeme: &self.lexeme(),
+            lexeme: self.lexeme(),
         }
     }
 
     fn skip_whitespace(&self) {
-        loop {
-            match self.peek() {
-                Some(c) => match c {
-                    ' ' | '\r' | '\t' => {
-                        *self.current.borrow_mut() += 1;
-                    }
-                    '\n' => {
-                        *self.current.borrow_mut() += 1;
-                        *self.line.borrow_mut() += 1;
-                    }
-                    _ => break,
-                    // TODO: add logic for comments
-                },
-                None => break,
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\r' | '\t' => {
+                    *self.current.borrow_mut() += 1;
+                }
+                '\n' => {
+                    *self.current.borrow_mut() += 1;
+                    *self.line.borrow_mut() += 1;
+                }
+                _ => break,
+                // TODO: add logic for comments
             }
         }
     }
@@ -273,3 +623,76 @@ impl<'s> Scanner<'s> {
         // char
     }
 }
+
+// Turns the lexeme of a string or interpolation token into its runtime
+// contents: strips the delimiters (including the `}` and `${` around
+// interpolated segments), removes the common indentation of `"""` block
+// strings and translates escape sequences unless the literal is raw.
+pub fn string_value(lexeme: &str) -> String {
+    let (raw, lexeme) = match lexeme.strip_prefix('r') {
+        Some(rest) => (true, rest),
+        None => (false, lexeme),
+    };
+
+    let contents = if lexeme.len() >= 6 && lexeme.starts_with("\"\"\"") {
+        dedent(&lexeme[3..lexeme.len() - 3])
+    } else {
+        // A lexeme too short to have both delimiters has no contents.
+        let end = if lexeme.ends_with("${") { 2 } else { 1 };
+        let end = lexeme.len().saturating_sub(end);
+        lexeme.get(1..end).unwrap_or_default().to_string()
+    };
+
+    if raw {
+        contents
+    } else {
+        unescape(&contents)
+    }
+}
+
+// Block strings may start right after the opening quotes and put the closing
+// quotes on their own line; both of those line breaks are dropped before the
+// smallest indentation shared by the non-blank lines is removed.
+fn dedent(text: &str) -> String {
+    let text = text
+        .strip_prefix("\r\n")
+        .or_else(|| text.strip_prefix('\n'))
+        .unwrap_or(text);
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    if lines.len() > 1 && lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+
+    let indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches([' ', '\t']).len())
+        .min()
+        .unwrap_or(0);
+
+    lines
+        .iter()
+        .map(|line| line.get(indent..).unwrap_or("").trim_end_matches('\r'))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('0') => result.push('\0'),
+            Some(other) => result.push(other),
+            None => {}
+        }
+    }
+    result
+}
@@ -5,6 +5,10 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
+    Question,
     Comma,
     Dot,
     Minus,
@@ -12,6 +16,11 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
     // One or two character tokens.
     Bang,
     BangEqual,
@@ -21,25 +30,54 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    DotDot,
+    DotDotDot,
+    LessLess,
+    GreaterGreater,
+    Arrow,
+    QuestionQuestion,
+    StarStar,
+    SlashSlash,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    PercentEqual,
+    PlusPlus,
+    MinusMinus,
     // Literals.
     Identifier,
     String,
+    // A string segment that ends where an interpolated `${` expression starts.
+    Interpolation,
     Number,
     // Keywords.
     And,
+    As,
+    Break,
+    Catch,
     Class,
+    Const,
+    Continue,
     Else,
     False,
+    Finally,
     For,
     Fun,
     If,
+    Import,
+    In,
+    Match,
     Nil,
     Or,
     Print,
     Return,
+    Static,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
 
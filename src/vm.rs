@@ -1,14 +1,80 @@
 use crate::{
+    bigint::BigInt,
     chunk::{Chunk, OpCode, Value},
     compiler::Compiler,
+    globals::Globals,
+    memory::{Heap, ObjRef},
+    object::{
+        BoundMethod, Closure, FunctionKind, Instance, LoxClass, LoxError, LoxFunction, LoxIterator,
+        LoxMap, MapKey, Module, Obj, Upvalue,
+    },
 };
-use std::collections::{hash_map, HashMap};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{fs, mem};
+
+const FRAMES_MAX: usize = 256;
+
+const NO_METHODS: &str = "Only instances, classes, lists, maps, strings and ints have methods.";
+const NO_PROPERTIES: &str = "Only instances, classes, errors and modules have properties.";
+const NO_FIELDS: &str = "Only instances and modules have fields.";
+const NOT_CALLABLE: &str = "Can only call functions and classes.";
 
 pub struct VM {
-    ip: usize,
-    chunk: Option<Chunk>,
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
-    globals_table: HashMap<String, Value>,
+    // Every module loaded so far, by canonical path.
+    modules: HashMap<PathBuf, ObjRef>,
+    // Upvalues still pointing into the stack.
+    open_upvalues: Vec<ObjRef>,
+    heap: Heap,
+    handlers: Vec<Handler>,
+    // The value being thrown while the stack unwinds to a handler.
+    exception: Option<Value>,
+    // Reports interning statistics once the script is done, however it
+    // ended.
+    debug: bool,
+}
+
+// A function or module top level being run. Its locals live on the stack
+// from `slots` upwards, starting with the callee itself.
+struct CallFrame {
+    closure: ObjRef,
+    module: ObjRef,
+    ip: usize,
+    slots: usize,
+    // Which parameters were left out of the call and get their default
+    // value. Empty when every argument was supplied.
+    missing: Vec<bool>,
+    returns: Returns,
+}
+
+// What a method the VM calls by itself has to return, and how the result is
+// used.
+#[derive(Clone, Copy)]
+enum Returns {
+    Anything,
+    // From `__str__`.
+    String,
+    // From `iter`: something a `for` loop can iterate over.
+    Iterable,
+    // From `next`: the value for the next iteration of a `for` loop, or nil
+    // to leave it by jumping forward by the offset.
+    LoopValue(u16),
+    // From a getter `Invoke` runs: the function to call with that many
+    // arguments, which wait below the receiver it replaces.
+    Callee(u8),
+    // From `__setindex__`: nothing, the assigned value is left below it.
+    Nothing,
+}
+
+// Installed by `try`: where to continue when an exception is thrown and how
+// far to unwind the frames and the stack before pushing the exception there.
+struct Handler {
+    frame_count: usize,
+    catch_ip: usize,
+    stack_depth: usize,
 }
 
 pub enum InterpretResult {
@@ -17,27 +83,253 @@ pub enum InterpretResult {
     RuntimeError,
 }
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
     pub fn new() -> Self {
         Self {
-            ip: 0,
-            chunk: None,
+            frames: Vec::new(),
             stack: Vec::new(),
-            globals_table: HashMap::new(),
+            modules: HashMap::new(),
+            open_upvalues: Vec::new(),
+            heap: Heap::new(),
+            handlers: Vec::new(),
+            exception: None,
+            debug: false,
+        }
+    }
+
+    pub fn set_stress_gc(&mut self, enabled: bool) {
+        self.heap.set_stress(enabled);
+    }
+
+    pub fn set_debug(&mut self, enabled: bool) {
+        self.debug = enabled;
+    }
+
+    pub fn interpret(&mut self, path: &Path, source: &str) -> InterpretResult {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let result = if self.load_module(path, source) {
+            self.run()
+        } else {
+            InterpretResult::CompileError
+        };
+        if self.debug {
+            eprint!("{}", self.heap.intern_stats());
         }
+        result
     }
 
-    pub fn interpret(&mut self, source: &str) -> InterpretResult {
+    // Compiles a module and pushes a frame for its top level. The module
+    // object goes on the stack below the frame, so it is what remains there
+    // once the top level returns.
+    fn load_module(&mut self, path: PathBuf, source: &str) -> bool {
+        let module = self.alloc(Obj::Module(Module {
+            path: path.clone(),
+            globals: Globals::new(),
+            loaded: false,
+        }));
+        self.modules.insert(path.clone(), module);
+        self.stack.push(Value::Obj(module));
+
+        // The compiler may collect garbage while it allocates constants, so
+        // it has to know about everything the VM keeps alive.
+        let roots = self.roots();
         let mut chunk = Chunk::new();
-        let compiler = Compiler::new(source);
+        let mut globals = mem::take(&mut self.module_mut(module).globals);
+        let compiled =
+            Compiler::new(source).compile(&mut chunk, &mut self.heap, &mut globals, &roots);
+        self.module_mut(module).globals = globals;
+        if !compiled {
+            self.stack.pop();
+            self.modules.remove(&path);
+            return false;
+        }
 
-        if !compiler.compile(&mut chunk) {
-            return InterpretResult::CompileError;
+        // Until the function exists its constants are only reachable
+        // through the chunk.
+        if self.heap.should_collect() {
+            self.mark_roots();
+            for value in &chunk.constants {
+                self.heap.mark_value(value);
+            }
+            self.heap.collect();
+        }
+        let function = self.heap.alloc(Obj::Function(LoxFunction {
+            kind: FunctionKind::Script,
+            name: None,
+            arity: 0,
+            optional: 0,
+            variadic: false,
+            parameters: Vec::new(),
+            upvalues: Vec::new(),
+            chunk,
+        }));
+        self.stack.push(Value::Obj(function));
+        let closure = self.alloc(Obj::Closure(Closure {
+            function,
+            module,
+            upvalues: Vec::new(),
+        }));
+        self.stack.pop();
+        self.frames.push(CallFrame {
+            closure,
+            module,
+            ip: 0,
+            slots: self.stack.len() - 1,
+            missing: Vec::new(),
+            returns: Returns::Anything,
+        });
+        true
+    }
+
+    // Paths are relative to the directory of the importing module.
+    fn import(&mut self, path: &str) -> Result<(), String> {
+        let importer = &self.module(self.frame().module).path;
+        let joined = importer.parent().unwrap_or(Path::new("")).join(path);
+        let Ok(resolved) = joined.canonicalize() else {
+            return Err(format!("Could not find module '{}'.", path));
         };
 
-        self.chunk = Some(chunk);
+        if let Some(module) = self.modules.get(&resolved).copied() {
+            if !self.module(module).loaded {
+                return Err(self.import_cycle(&resolved));
+            }
+            self.stack.push(Value::Obj(module));
+            return Ok(());
+        }
+
+        let source = fs::read_to_string(&resolved)
+            .map_err(|err| format!("Could not read module '{}': {}.", path, err))?;
+        if !self.load_module(resolved, &source) {
+            return Err(format!("Could not compile module '{}'.", path));
+        }
+        Ok(())
+    }
+
+    // Every frame belongs to a module that is still loading, so the frames
+    // spell out the chain of imports that led back to `path`.
+    fn import_cycle(&self, path: &Path) -> String {
+        let mut chain: Vec<String> = Vec::new();
+        for frame in &self.frames {
+            let name = module_name(&self.module(frame.module).path);
+            if chain.last() != Some(&name) {
+                chain.push(name);
+            }
+        }
+        chain.push(module_name(path));
+        format!("Import cycle detected: {}.", chain.join(" -> "))
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("Expected a call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("Expected a call frame")
+    }
+
+    fn closure(&self, closure: ObjRef) -> &Closure {
+        match self.heap.get(closure) {
+            Obj::Closure(closure) => closure,
+            _ => unreachable!("Expected a closure"),
+        }
+    }
+
+    fn function(&self, closure: ObjRef) -> &LoxFunction {
+        match self.heap.get(self.closure(closure).function) {
+            Obj::Function(function) => function,
+            _ => unreachable!("Expected a function"),
+        }
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.function(self.frame().closure).chunk
+    }
+
+    fn class(&self, class: ObjRef) -> &LoxClass {
+        match self.heap.get(class) {
+            Obj::Class(class) => class,
+            _ => unreachable!("Expected a class"),
+        }
+    }
+
+    fn module(&self, module: ObjRef) -> &Module {
+        match self.heap.get(module) {
+            Obj::Module(module) => module,
+            _ => unreachable!("Expected a module"),
+        }
+    }
+
+    fn module_mut(&mut self, module: ObjRef) -> &mut Module {
+        match self.heap.get_mut(module) {
+            Obj::Module(module) => module,
+            _ => unreachable!("Expected a module"),
+        }
+    }
+
+    fn globals(&self) -> &Globals {
+        &self.module(self.frame().module).globals
+    }
+
+    fn globals_mut(&mut self) -> &mut Globals {
+        let module = self.frame().module;
+        &mut self.module_mut(module).globals
+    }
+
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        self.collect_if_needed();
+        self.heap.alloc(obj)
+    }
+
+    fn intern(&mut self, string: String) -> ObjRef {
+        if let Some(handle) = self.heap.find_string(&string) {
+            return handle;
+        }
+        self.collect_if_needed();
+        self.heap.intern(string)
+    }
+
+    fn intern_big_int(&mut self, int: BigInt) -> ObjRef {
+        if let Some(handle) = self.heap.find_big_int(&int) {
+            return handle;
+        }
+        self.collect_if_needed();
+        self.heap.intern_big_int(int)
+    }
+
+    fn collect_if_needed(&mut self) {
+        if self.heap.should_collect() {
+            self.mark_roots();
+            self.heap.collect();
+        }
+    }
+
+    fn mark_roots(&mut self) {
+        for value in self.roots() {
+            self.heap.mark_value(&value);
+        }
+    }
 
-        self.run()
+    // Module globals and chunk constants are reached through the frames and
+    // the module cache.
+    fn roots(&self) -> Vec<Value> {
+        let mut roots = self.stack.clone();
+        for frame in &self.frames {
+            roots.push(Value::Obj(frame.closure));
+        }
+        roots.extend(
+            self.open_upvalues
+                .iter()
+                .map(|upvalue| Value::Obj(*upvalue)),
+        );
+        roots.extend(self.modules.values().map(|module| Value::Obj(*module)));
+        roots.extend(self.exception);
+        roots
     }
 
     fn peek(&self, distance: usize) -> &Value {
@@ -45,87 +337,1500 @@ impl VM {
     }
 
     fn run(&mut self) -> InterpretResult {
-        while self.ip
-            < self
-                .chunk
-                .as_ref()
-                .expect("Chunk should be initialized")
-                .code
-                .len()
-        {
-            dbg!(&self.chunk);
-            let chunk = self.chunk.as_ref().expect("Chunk should be initialized");
-            let opcode = &chunk.code[self.ip];
-            self.ip += 1;
+        loop {
+            match self.execute() {
+                InterpretResult::RuntimeError => {
+                    if !self.handle_exception() {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Sends the pending exception to the innermost handler, or reports it
+    // and returns false when nothing catches it.
+    fn handle_exception(&mut self) -> bool {
+        let exception = self.exception.take().expect("Expected an exception");
+        if let Some(handler) = self.handlers.pop() {
+            // A module abandoned halfway is loaded from scratch when it is
+            // imported again.
+            for frame in self.frames.split_off(handler.frame_count) {
+                let module = self.module(frame.module);
+                if !module.loaded {
+                    let path = module.path.clone();
+                    self.modules.remove(&path);
+                }
+            }
+            self.close_upvalues(handler.stack_depth);
+            self.stack.truncate(handler.stack_depth);
+            self.stack.push(exception);
+            self.frame_mut().ip = handler.catch_ip;
+            return true;
+        }
+
+        let line = match exception {
+            Value::Obj(handle) => match self.heap.get(handle) {
+                Obj::Error(error) => {
+                    eprintln!("{}", error.message);
+                    Some(error.line)
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        let line = line.unwrap_or_else(|| {
+            eprintln!("Uncaught exception: {}", self.heap.format_value(&exception));
+            self.current_line()
+        });
+        eprintln!("[line {}] in script", line);
+        false
+    }
+
+    fn execute(&mut self) -> InterpretResult {
+        loop {
+            let frame = self.frames.last_mut().expect("Expected a call frame");
+            let slots = frame.slots;
+            let ip = frame.ip;
+            frame.ip += 1;
+            let opcode = self.chunk().code[ip];
+
+            if let Some(overload) = self.overload(opcode) {
+                if let Err(message) = overload
+                    .and_then(|(method, reflected)| self.call_operator(opcode, method, reflected))
+                {
+                    return self.runtime_error(&message);
+                }
+                continue;
+            }
+            let chunk = self.chunk();
 
             match opcode {
                 OpCode::Constant(index) => {
-                    let constant = chunk.constants[*index as usize].clone();
+                    let constant = chunk.constants[index as usize];
                     self.stack.push(constant);
                 }
+                OpCode::ConstantLong(index) => {
+                    let constant = chunk.constants[index as usize];
+                    self.stack.push(constant);
+                }
+                OpCode::Nil => self.stack.push(Value::Nil),
+                OpCode::True => self.stack.push(Value::Boolean(true)),
+                OpCode::False => self.stack.push(Value::Boolean(false)),
+                OpCode::Pop => {
+                    self.stack.pop();
+                }
+                OpCode::Dup(distance) => {
+                    let value = *self.peek(distance as usize);
+                    self.stack.push(value);
+                }
+                OpCode::Bury(depth) => {
+                    let value = self.stack.pop().unwrap();
+                    let index = self.stack.len() - depth as usize;
+                    // Captured locals can't move with their upvalues open.
+                    self.close_upvalues(index);
+                    self.stack.insert(index, value);
+                }
+                OpCode::GetLocal(slot) => {
+                    let value = self.stack[slots + slot as usize];
+                    self.stack.push(value);
+                }
+                OpCode::SetLocal(slot) => {
+                    self.stack[slots + slot as usize] = *self.peek(0);
+                }
+                OpCode::Negate => {
+                    let result = match self.number(self.peek(0)) {
+                        Some(Number::Int(int)) => match int.checked_neg() {
+                            Some(int) => Number::Int(int),
+                            None => Number::Big(BigInt::from_i64(int).neg()),
+                        },
+                        Some(Number::Big(int)) => Number::Big(int.neg()),
+                        Some(Number::Float(number)) => Number::Float(-number),
+                        None => return self.runtime_error("Operand must be a number."),
+                    };
+                    let result = self.number_value(result);
+                    self.stack.pop();
+                    self.stack.push(result);
+                }
+                OpCode::BitNot => {
+                    let result = match self.number(self.peek(0)).and_then(Number::into_integer) {
+                        Some(Number::Int(int)) => Number::Int(!int),
+                        Some(int) => Number::Big(int.to_big().not()),
+                        None => return self.runtime_error("Operand must be an integer."),
+                    };
+                    let result = self.number_value(result);
+                    self.stack.pop();
+                    self.stack.push(result);
+                }
+                OpCode::BitAnd
+                | OpCode::BitOr
+                | OpCode::BitXor
+                | OpCode::ShiftLeft
+                | OpCode::ShiftRight => {
+                    let (Some(a), Some(b)) = (
+                        self.number(self.peek(1)).and_then(Number::into_integer),
+                        self.number(self.peek(0)).and_then(Number::into_integer),
+                    ) else {
+                        return self.runtime_error("Operands must be integers.");
+                    };
+                    let result = match bitwise(opcode, a, b) {
+                        Ok(result) => self.number_value(result),
+                        Err(message) => return self.runtime_error(message),
+                    };
+                    self.stack.truncate(self.stack.len() - 2);
+                    self.stack.push(result);
+                }
                 OpCode::Add => {
+                    let (a, b) = (*self.peek(1), *self.peek(0));
+                    let result = match (self.number(&a), self.number(&b)) {
+                        (Some(a), Some(b)) => match arithmetic(opcode, a, b) {
+                            Ok(result) => self.number_value(result),
+                            Err(message) => return self.runtime_error(message),
+                        },
+                        _ => match (self.heap.as_str(&a), self.heap.as_str(&b)) {
+                            (Some(a), Some(b)) => Value::Obj(self.intern(format!("{}{}", a, b))),
+                            _ => {
+                                return self
+                                    .runtime_error("Operands must be two numbers or two strings.");
+                            }
+                        },
+                    };
+                    self.stack.truncate(self.stack.len() - 2);
+                    self.stack.push(result);
+                }
+                OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::IntDivide
+                | OpCode::Modulo
+                | OpCode::Power => {
+                    let (Some(a), Some(b)) = (self.number(self.peek(1)), self.number(self.peek(0)))
+                    else {
+                        return self.runtime_error("Operands must be numbers.");
+                    };
+                    // The operands stay on the stack until a big int result
+                    // has been allocated.
+                    let result = match arithmetic(opcode, a, b) {
+                        Ok(result) => self.number_value(result),
+                        Err(message) => return self.runtime_error(message),
+                    };
+                    self.stack.truncate(self.stack.len() - 2);
+                    self.stack.push(result);
+                }
+                OpCode::Print => {
+                    let value = self.stack.pop().unwrap();
+                    println!("{}", self.heap.format_value(&value));
+                }
+                OpCode::ToString => {
+                    let value = *self.peek(0);
+                    if self.heap.as_str(&value).is_none() {
+                        // Keep the operand on the stack while allocating so a
+                        // collection cannot free anything it points to.
+                        let handle = self.intern(self.heap.format_value(&value));
+                        self.stack.pop();
+                        self.stack.push(Value::Obj(handle));
+                    }
+                }
+
+                OpCode::Equal => {
                     let b = self.stack.pop().unwrap();
                     let a = self.stack.pop().unwrap();
-                    match (a, b) {
-                        (Value::Number(a), Value::Number(b)) => {
-                            self.stack.push(Value::Number(a + b));
-                        }
-                        _ => return InterpretResult::RuntimeError,
-                    }
+                    self.stack.push(Value::Boolean(self.values_equal(&a, &b)));
                 }
-                OpCode::Divide => {
+                OpCode::Greater | OpCode::Less => {
                     let b = self.stack.pop().unwrap();
                     let a = self.stack.pop().unwrap();
-                    match (a, b) {
-                        (Value::Number(a), Value::Number(b)) => {
-                            self.stack.push(Value::Number(a / b));
-                        }
-                        _ => return InterpretResult::RuntimeError
+                    let (Some(a), Some(b)) = (self.number(&a), self.number(&b)) else {
+                        return self.runtime_error("Operands must be numbers.");
+                    };
+                    let ordering = compare_numbers(&a, &b);
+                    let result = match opcode {
+                        OpCode::Greater => ordering.is_gt(),
+                        _ => ordering.is_lt(),
+                    };
+                    self.stack.push(Value::Boolean(result));
+                }
+                OpCode::Not => {
+                    let value = self.stack.pop().unwrap();
+                    let is_falsey = matches!(value, Value::Nil | Value::Boolean(false));
+                    self.stack.push(Value::Boolean(is_falsey));
+                }
+
+                OpCode::DefineGlobal(slot) => {
+                    let value = *self.peek(0);
+                    self.globals_mut().set(slot, value);
+                    self.stack.pop();
+                }
+
+                OpCode::GetGlobal(slot) => match self.globals().get(slot) {
+                    Some(value) => {
+                        self.stack.push(value);
                     }
+                    None => return self.undefined_variable(slot),
+                },
 
+                // Assignment never creates a global, it only replaces the
+                // value of one that has been defined already.
+                OpCode::SetGlobal(slot) => {
+                    if !self.globals().is_defined(slot) {
+                        return self.undefined_variable(slot);
+                    }
+                    if self.globals().is_const(slot) {
+                        let name = self
+                            .heap
+                            .format_value(&Value::Obj(self.globals().name(slot)));
+                        return self
+                            .runtime_error(&format!("Can't assign to constant '{}'.", name));
+                    }
+                    let value = *self.peek(0);
+                    self.globals_mut().set(slot, value);
                 }
-                OpCode::Print => {
+
+                OpCode::BuildList(count) => {
+                    // The elements stay on the stack until the list exists so
+                    // a collection triggered by the allocation can see them.
+                    let start = self.stack.len() - count as usize;
+                    let items = self.stack[start..].to_vec();
+                    let list = self.alloc(Obj::List(items));
+                    self.stack.truncate(start);
+                    self.stack.push(Value::Obj(list));
+                }
+                OpCode::BuildMap(count) => {
+                    let start = self.stack.len() - 2 * count as usize;
+                    // Allocated first, so big ints interned for its keys are
+                    // reachable through it.
+                    let map = self.alloc(Obj::Map(LoxMap::default()));
+                    for index in (start..self.stack.len()).step_by(2) {
+                        let (key, value) = (self.stack[index], self.stack[index + 1]);
+                        match self.insert_key(&key) {
+                            Ok(key) => self.map_entries_mut(map).insert(key, value),
+                            Err(message) => return self.runtime_error(message),
+                        }
+                    }
+                    self.stack.truncate(start);
+                    self.stack.push(Value::Obj(map));
+                }
+                OpCode::Range => {
+                    let (Some(start), Some(end)) =
+                        (exact_integer(self.peek(1)), exact_integer(self.peek(0)))
+                    else {
+                        return self.runtime_error("Range bounds must be integers.");
+                    };
+                    let range = self.alloc(Obj::Range(start, end));
+                    self.stack.truncate(self.stack.len() - 2);
+                    self.stack.push(Value::Obj(range));
+                }
+                // An instance with a `next` method is its own iterator. One
+                // with an `iter` method is replaced by what that returns,
+                // and the instruction runs again on it.
+                OpCode::GetIter if self.instance_class(self.peek(0)).is_some() => {
+                    let class = self.instance_class(self.peek(0)).unwrap();
+                    if self.special_method(class, "next").is_some() {
+                        continue;
+                    }
+                    let Some(iter) = self.special_method(class, "iter") else {
+                        let class = self.heap.format_value(&Value::Obj(self.class(class).name));
+                        let message = format!("'{}' has no 'iter' or 'next' method.", class);
+                        return self.runtime_error(&message);
+                    };
+                    self.frame_mut().ip -= 1;
+                    if let Err(message) = self.call_special(iter, 0, Returns::Iterable) {
+                        return self.runtime_error(&message);
+                    }
+                }
+                OpCode::GetIter => {
+                    let iterable = match *self.peek(0) {
+                        Value::Obj(handle) => match self.heap.get(handle) {
+                            Obj::List(_) | Obj::Map(_) | Obj::String(_) | Obj::Range(..) => {
+                                Some(handle)
+                            }
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    let Some(iterable) = iterable else {
+                        return self.runtime_error(
+                            "Can only iterate over lists, maps, strings, ranges and instances.",
+                        );
+                    };
+                    let iterator = self.alloc(Obj::Iterator(LoxIterator {
+                        iterable,
+                        position: 0,
+                    }));
+                    self.stack.pop();
+                    self.stack.push(Value::Obj(iterator));
+                }
+                // The value `next` returns becomes the loop variable, unless
+                // it is nil and ends the loop.
+                OpCode::ForIter(slot, offset)
+                    if self
+                        .instance_class(&self.stack[slots + slot as usize])
+                        .is_some() =>
+                {
+                    let iterator = self.stack[slots + slot as usize];
+                    let class = self.instance_class(&iterator).unwrap();
+                    let Some(next) = self.special_method(class, "next") else {
+                        unreachable!("Expected an iterator");
+                    };
+                    self.stack.push(iterator);
+                    if let Err(message) = self.call_special(next, 0, Returns::LoopValue(offset)) {
+                        return self.runtime_error(&message);
+                    }
+                }
+                OpCode::ForIter(slot, offset) => {
+                    let Value::Obj(iterator) = self.stack[slots + slot as usize] else {
+                        unreachable!("For loop without an iterator");
+                    };
+                    match self.iterator_next(iterator) {
+                        Some(value) => self.stack.push(value),
+                        None => self.frame_mut().ip += offset as usize,
+                    }
+                }
+                OpCode::Jump(offset) => self.frame_mut().ip += offset as usize,
+                OpCode::JumpIfFalse(offset) => {
+                    if matches!(self.peek(0), Value::Nil | Value::Boolean(false)) {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::JumpIfNotNil(offset) => {
+                    if *self.peek(0) != Value::Nil {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::Loop(offset) => self.frame_mut().ip -= offset as usize,
+                OpCode::GetIndex => {
+                    let index = self.stack.pop().unwrap();
+                    let target = self.stack.pop().unwrap();
+                    match self.get_index(&target, &index) {
+                        Ok(value) => self.stack.push(value),
+                        Err(message) => return self.runtime_error(&message),
+                    }
+                }
+                OpCode::UnpackList(count) => {
+                    let items = match *self.peek(0) {
+                        Value::Obj(handle) => match self.heap.get(handle) {
+                            Obj::List(items) => Some(items),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    let items = match items {
+                        Some(items) if items.len() == count as usize => items.clone(),
+                        Some(items) => {
+                            let message = format!(
+                                "Expected a list of {} elements but got {}.",
+                                count,
+                                items.len()
+                            );
+                            return self.runtime_error(&message);
+                        }
+                        None => {
+                            return self
+                                .runtime_error("Only lists can be destructured with '[...]'.")
+                        }
+                    };
+                    self.stack.pop();
+                    self.stack.extend(items);
+                }
+                OpCode::MatchList(count) => {
+                    let value = self.stack.pop().unwrap();
+                    let matches = match value {
+                        Value::Obj(handle) => match self.heap.get(handle) {
+                            Obj::List(items) => items.len() == count as usize,
+                            _ => false,
+                        },
+                        _ => false,
+                    };
+                    self.stack.push(Value::Boolean(matches));
+                }
+                OpCode::SetIndex => {
                     let value = self.stack.pop().unwrap();
-                    match value {
-                        Value::String(str) => println!("{:#?}", str),
-                        Value::Number(numb) => println!("{}", numb),
-                        Value::Boolean(bool) => println!("{}", bool),
-                        _ => println!("Nil"),
+                    let index = self.stack.pop().unwrap();
+                    let target = self.stack.pop().unwrap();
+                    match self.set_index(&target, &index, value) {
+                        Ok(()) => self.stack.push(value),
+                        Err(message) => return self.runtime_error(&message),
+                    }
+                }
+                // Functions of a module are looked up like any of its other
+                // globals and then called.
+                OpCode::Invoke(name, arg_count)
+                    if self.is_module(self.peek(arg_count as usize)) =>
+                {
+                    let Value::Obj(name) = chunk.constants[name as usize] else {
+                        unreachable!("Expected a method name");
+                    };
+                    let receiver = *self.peek(arg_count as usize);
+                    let result = self.get_property(&receiver, name).and_then(|callee| {
+                        let base = self.stack.len() - arg_count as usize - 1;
+                        self.stack[base] = callee;
+                        self.call_value(callee, arg_count, &[])
+                    });
+                    if let Err(message) = result {
+                        return self.runtime_error(&message);
+                    }
+                }
+                OpCode::Invoke(name, arg_count)
+                    if self.has_members(self.peek(arg_count as usize)) =>
+                {
+                    let Value::Obj(name) = chunk.constants[name as usize] else {
+                        unreachable!("Expected a method name");
+                    };
+                    if let Err(message) = self.invoke_member(name, arg_count) {
+                        return self.runtime_error(&message);
+                    }
+                }
+                OpCode::Invoke(name, arg_count) => {
+                    let name = chunk.constants[name as usize];
+                    let receiver = *self.peek(arg_count as usize);
+                    let result = match receiver {
+                        Value::Obj(handle) => match self.heap.get(handle) {
+                            Obj::List(_) => self.invoke_list_method(handle, &name, arg_count),
+                            Obj::Map(_) => self.invoke_map_method(handle, &name, arg_count),
+                            Obj::String(_) => self.invoke_string_method(handle, &name, arg_count),
+                            Obj::BigInt(_) => self.invoke_int_method(&receiver, &name, arg_count),
+                            _ => Err(NO_METHODS.to_string()),
+                        },
+                        Value::Int(_) => self.invoke_int_method(&receiver, &name, arg_count),
+                        _ => Err(NO_METHODS.to_string()),
+                    };
+                    match result {
+                        Ok(value) => {
+                            let base = self.stack.len() - arg_count as usize - 1;
+                            self.stack.truncate(base);
+                            self.stack.push(value);
+                        }
+                        Err(message) => return self.runtime_error(&message),
                     }
                 }
 
-                OpCode::DefineGlobal(index) => {
-                    if let Value::String(key) = &chunk.constants[*index as usize] {
-                        let value = self.peek(0).clone();
-                        self.globals_table.insert(key.clone(), value);
-                        self.stack.pop();
-                    } else {
-                        // Handle error case - the constant wasn't a string
+                OpCode::GetProperty(name) => {
+                    let Value::Obj(name) = chunk.constants[name as usize] else {
+                        unreachable!("Expected a property name");
+                    };
+                    let target = *self.peek(0);
+                    // The getter runs with the target in its receiver slot,
+                    // which its result replaces.
+                    if let Some(getter) = self.accessor(&target, name, false) {
+                        if let Err(message) = self.call_value(Value::Obj(getter), 0, &[]) {
+                            return self.runtime_error(&message);
+                        }
+                        continue;
+                    }
+                    match self.get_property(&target, name) {
+                        Ok(value) => {
+                            self.stack.pop();
+                            self.stack.push(value);
+                        }
+                        Err(message) => return self.runtime_error(&message),
+                    }
+                }
+                OpCode::SetProperty(name) => {
+                    let Value::Obj(name) = chunk.constants[name as usize] else {
+                        unreachable!("Expected a property name");
+                    };
+                    // A setter takes the value as its argument and returns
+                    // it, leaving it on the stack like any assignment.
+                    if let Some(setter) = self.accessor(self.peek(1), name, true) {
+                        if let Err(message) = self.call_value(Value::Obj(setter), 1, &[]) {
+                            return self.runtime_error(&message);
+                        }
+                        continue;
+                    }
+                    let value = self.stack.pop().unwrap();
+                    let target = self.stack.pop().unwrap();
+                    match self.set_property(&target, name, value) {
+                        Ok(()) => self.stack.push(value),
+                        Err(message) => return self.runtime_error(&message),
+                    }
+                }
+                OpCode::Class(name) => {
+                    let Value::Obj(name) = chunk.constants[name as usize] else {
+                        unreachable!("Expected a class name");
+                    };
+                    let class = self.alloc(Obj::Class(LoxClass::new(name)));
+                    self.stack.push(Value::Obj(class));
+                }
+                OpCode::Method(name)
+                | OpCode::StaticMethod(name)
+                | OpCode::Getter(name)
+                | OpCode::Setter(name) => {
+                    let Value::Obj(name) = chunk.constants[name as usize] else {
+                        unreachable!("Expected a method name");
+                    };
+                    let Some(Value::Obj(method)) = self.stack.pop() else {
+                        unreachable!("Expected a method");
+                    };
+                    let is_initializer = self.function(method).kind == FunctionKind::Initializer;
+                    let Value::Obj(class) = *self.peek(0) else {
+                        unreachable!("Expected a class");
+                    };
+                    let Obj::Class(class) = self.heap.get_mut(class) else {
+                        unreachable!("Expected a class");
+                    };
+                    let members = match opcode {
+                        OpCode::Method(_) => &mut class.methods,
+                        OpCode::StaticMethod(_) => &mut class.static_methods,
+                        OpCode::Getter(_) => &mut class.getters,
+                        _ => &mut class.setters,
+                    };
+                    members.insert(name, method);
+                    if is_initializer {
+                        class.initializer = Some(method);
+                    }
+                }
+                OpCode::Throw => {
+                    self.exception = self.stack.pop();
+                    return InterpretResult::RuntimeError;
+                }
+                OpCode::PushHandler(offset) => self.handlers.push(Handler {
+                    frame_count: self.frames.len(),
+                    catch_ip: ip + 1 + offset as usize,
+                    stack_depth: self.stack.len(),
+                }),
+                OpCode::PopHandler => {
+                    self.handlers.pop();
+                }
+                OpCode::EndFinally => {
+                    let rethrow = self.stack.pop().unwrap();
+                    let exception = self.stack.pop().unwrap();
+                    if rethrow == Value::Boolean(true) {
+                        self.exception = Some(exception);
                         return InterpretResult::RuntimeError;
                     }
                 }
 
-                OpCode::GetGlobal(index) => {
-                    if let Value::String(key) = &chunk.constants[*index as usize] {
-                        match self.globals_table.get(key) {
-                            Some(value) => {
-                                self.stack.push(value.clone());
-                            }
-                            None => {
-                                eprintln!("Undefined variable '{}'", key);
-                                return InterpretResult::RuntimeError;
-                            }
+                OpCode::Import(path) => {
+                    let path = self.heap.format_value(&chunk.constants[path as usize]);
+                    if let Err(message) = self.import(&path) {
+                        return self.runtime_error(&message);
+                    }
+                }
+
+                OpCode::Call(arg_count) => {
+                    let callee = *self.peek(arg_count as usize);
+                    if let Err(message) = self.call_value(callee, arg_count, &[]) {
+                        return self.runtime_error(&message);
+                    }
+                }
+                OpCode::CallNamed(arg_count, names) => {
+                    let Value::Obj(names) = chunk.constants[names as usize] else {
+                        unreachable!("Expected a list of names");
+                    };
+                    let names: Vec<String> = self
+                        .list_items(names)
+                        .iter()
+                        .map(|name| self.heap.format_value(name))
+                        .collect();
+                    let callee = *self.peek(arg_count as usize);
+                    if let Err(message) = self.call_value(callee, arg_count, &names) {
+                        return self.runtime_error(&message);
+                    }
+                }
+                OpCode::CallSpread(parts) => {
+                    let start = self.stack.len() - parts as usize;
+                    let mut args = Vec::new();
+                    for part in &self.stack[start..] {
+                        let Value::Obj(handle) = *part else {
+                            return self.runtime_error("Only lists can be spread into arguments.");
+                        };
+                        let Obj::List(items) = self.heap.get(handle) else {
+                            return self.runtime_error("Only lists can be spread into arguments.");
+                        };
+                        args.extend_from_slice(items);
+                    }
+                    let Ok(arg_count) = u8::try_from(args.len()) else {
+                        return self.runtime_error("Can't have more than 255 arguments.");
+                    };
+                    self.stack.truncate(start);
+                    self.stack.extend(args);
+                    let callee = *self.peek(arg_count as usize);
+                    if let Err(message) = self.call_value(callee, arg_count, &[]) {
+                        return self.runtime_error(&message);
+                    }
+                }
+                OpCode::JumpIfSupplied(parameter, offset) => {
+                    let frame = self.frame_mut();
+                    if !frame
+                        .missing
+                        .get(parameter as usize)
+                        .copied()
+                        .unwrap_or(false)
+                    {
+                        frame.ip += offset as usize;
+                    }
+                }
+                OpCode::Closure(index) => {
+                    let Value::Obj(function) = chunk.constants[index as usize] else {
+                        unreachable!("Expected a function");
+                    };
+                    let Obj::Function(LoxFunction { upvalues, .. }) = self.heap.get(function)
+                    else {
+                        unreachable!("Expected a function");
+                    };
+                    let sources = upvalues.clone();
+                    let enclosing = self.frame().closure;
+                    let closure = self.alloc(Obj::Closure(Closure {
+                        function,
+                        module: self.frame().module,
+                        upvalues: Vec::with_capacity(sources.len()),
+                    }));
+                    // On the stack the closure survives the collections its
+                    // upvalue allocations may trigger.
+                    self.stack.push(Value::Obj(closure));
+                    for source in sources {
+                        let upvalue = if source.is_local {
+                            self.capture_upvalue(slots + source.index as usize)
+                        } else {
+                            self.closure(enclosing).upvalues[source.index as usize]
+                        };
+                        if let Obj::Closure(closure) = self.heap.get_mut(closure) {
+                            closure.upvalues.push(upvalue);
                         }
-                    } else {
-                        return InterpretResult::RuntimeError;
                     }
                 }
+                OpCode::GetUpvalue(index) => {
+                    let upvalue = self.closure(self.frame().closure).upvalues[index as usize];
+                    let value = match self.heap.get(upvalue) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => self.stack[*slot],
+                        Obj::Upvalue(Upvalue::Closed(value)) => *value,
+                        _ => unreachable!("Expected an upvalue"),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue(index) => {
+                    let upvalue = self.closure(self.frame().closure).upvalues[index as usize];
+                    let value = *self.peek(0);
+                    match self.heap.get_mut(upvalue) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => self.stack[*slot] = value,
+                        Obj::Upvalue(closed) => *closed = Upvalue::Closed(value),
+                        _ => unreachable!("Expected an upvalue"),
+                    }
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+
+                // A module's top level returns the module itself, which is
+                // what its importer receives.
+                OpCode::Return => {
+                    let result = self.stack.pop().expect("Expected a return value");
+                    let frame = self.frames.pop().expect("Expected a call frame");
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    match frame.returns {
+                        Returns::String if self.heap.as_str(&result).is_none() => {
+                            return self.runtime_error("'__str__' must return a string.");
+                        }
+                        Returns::Iterable if !self.is_iterable(&result) => {
+                            return self.runtime_error("'iter' must return an iterable.");
+                        }
+                        _ => {}
+                    }
+                    if self.function(frame.closure).kind == FunctionKind::Script {
+                        self.module_mut(frame.module).loaded = true;
+                    }
+                    if self.frames.is_empty() {
+                        return InterpretResult::Ok;
+                    }
+                    if let (Returns::LoopValue(offset), Value::Nil) = (frame.returns, result) {
+                        self.frame_mut().ip += offset as usize;
+                        continue;
+                    }
+                    if let Returns::Nothing = frame.returns {
+                        continue;
+                    }
+                    if let Returns::Callee(arg_count) = frame.returns {
+                        let base = self.stack.len() - arg_count as usize - 1;
+                        self.stack[base] = result;
+                        if let Err(message) = self.call_value(result, arg_count, &[]) {
+                            return self.runtime_error(&message);
+                        }
+                        continue;
+                    }
+                    self.stack.push(result);
+                }
+            }
+        }
+    }
+
+    // Instances expose their fields and methods, classes their static
+    // methods, error objects what went wrong and where and modules their
+    // globals. Methods come back bound to the target, which stays on the
+    // stack so it cannot be collected while they are allocated.
+    fn get_property(&mut self, target: &Value, name: ObjRef) -> Result<Value, String> {
+        let Value::Obj(handle) = *target else {
+            return Err(NO_PROPERTIES.to_string());
+        };
+        let method = match self.heap.get(handle) {
+            Obj::Error(error) => {
+                return match self.heap.as_str(&Value::Obj(name)) {
+                    Some("message") => {
+                        let message = error.message.clone();
+                        Ok(Value::Obj(self.intern(message)))
+                    }
+                    Some("line") => Ok(Value::Int(error.line as i64)),
+                    _ => Err(self.undefined_property(name)),
+                }
+            }
+            Obj::Module(module) => {
+                let globals = &module.globals;
+                return match globals.lookup(name).and_then(|slot| globals.get(slot)) {
+                    Some(value) => Ok(value),
+                    None => Err(self.undefined_property(name)),
+                };
+            }
+            Obj::Instance(instance) => match instance.fields.get(&name) {
+                Some(value) => return Ok(*value),
+                None => self.class(instance.class).methods.get(&name).copied(),
+            },
+            Obj::Class(class) => class.static_methods.get(&name).copied(),
+            _ => return Err(NO_PROPERTIES.to_string()),
+        };
+        let Some(method) = method else {
+            return Err(self.undefined_property(name));
+        };
+        let bound = self.alloc(Obj::BoundMethod(BoundMethod {
+            receiver: *target,
+            method,
+        }));
+        Ok(Value::Obj(bound))
+    }
+
+    fn undefined_property(&self, name: ObjRef) -> String {
+        let name = self.heap.format_value(&Value::Obj(name));
+        format!("Undefined property '{}'.", name)
+    }
+
+    // The getter, or setter, the class of an instance defines for the
+    // property.
+    fn accessor(&self, target: &Value, name: ObjRef, setter: bool) -> Option<ObjRef> {
+        let Value::Obj(handle) = target else {
+            return None;
+        };
+        let Obj::Instance(instance) = self.heap.get(*handle) else {
+            return None;
+        };
+        let class = self.class(instance.class);
+        let accessors = if setter {
+            &class.setters
+        } else {
+            &class.getters
+        };
+        accessors.get(&name).copied()
+    }
+
+    // Instances take any field, except for properties that only have a
+    // getter. Of a module, only the globals it has defined can be assigned
+    // from outside.
+    fn set_property(&mut self, target: &Value, name: ObjRef, value: Value) -> Result<(), String> {
+        let Value::Obj(handle) = *target else {
+            return Err(NO_FIELDS.to_string());
+        };
+        if let Obj::Instance(instance) = self.heap.get(handle) {
+            if self.class(instance.class).getters.contains_key(&name) {
+                let name = self.heap.format_value(&Value::Obj(name));
+                return Err(format!("Property '{}' has a getter but no setter.", name));
+            }
+        }
+        let module = match self.heap.get_mut(handle) {
+            Obj::Instance(instance) => {
+                instance.fields.insert(name, value);
+                return Ok(());
+            }
+            Obj::Module(module) => module,
+            _ => return Err(NO_FIELDS.to_string()),
+        };
+        match module.globals.lookup(name) {
+            Some(slot) if module.globals.is_const(slot) => {
+                let name = self.heap.format_value(&Value::Obj(name));
+                Err(format!("Can't assign to constant '{}'.", name))
+            }
+            Some(slot) if module.globals.is_defined(slot) => {
+                module.globals.set(slot, value);
+                Ok(())
+            }
+            _ => {
+                let name = self.heap.format_value(&Value::Obj(name));
+                Err(format!("Undefined property '{}'.", name))
+            }
+        }
+    }
+
+    // The arguments are on the stack above the callee, the last `names.len()`
+    // of them named. Bound methods and classes put the receiver in the
+    // callee's slot, where the method finds it as `this`.
+    fn call_value(&mut self, callee: Value, arg_count: u8, names: &[String]) -> Result<(), String> {
+        let Value::Obj(handle) = callee else {
+            return Err(NOT_CALLABLE.to_string());
+        };
+        let base = self.stack.len() - arg_count as usize - 1;
+        match self.heap.get(handle) {
+            Obj::Closure(_) => self.call_closure(handle, arg_count, names),
+            Obj::BoundMethod(bound) => {
+                let method = bound.method;
+                self.stack[base] = bound.receiver;
+                self.call_closure(method, arg_count, names)
+            }
+            Obj::Class(class) => {
+                let initializer = class.initializer;
+                // The class is still in the callee slot while the instance
+                // is allocated.
+                let instance = self.alloc(Obj::Instance(Instance {
+                    class: handle,
+                    fields: HashMap::new(),
+                }));
+                self.stack[base] = Value::Obj(instance);
+                match initializer {
+                    Some(initializer) => self.call_closure(initializer, arg_count, names),
+                    None if arg_count > 0 => {
+                        Err(format!("Expected 0 arguments but got {}.", arg_count))
+                    }
+                    None => Ok(()),
+                }
+            }
+            _ => Err(NOT_CALLABLE.to_string()),
+        }
+    }
+
+    fn call_closure(
+        &mut self,
+        closure: ObjRef,
+        arg_count: u8,
+        names: &[String],
+    ) -> Result<(), String> {
+        let module = self.closure(closure).module;
+        let function = self.function(closure);
+        let missing = if function.optional == 0 && !function.variadic && names.is_empty() {
+            if arg_count != function.arity {
+                return Err(format!(
+                    "Expected {} arguments but got {}.",
+                    function.arity, arg_count
+                ));
+            }
+            Vec::new()
+        } else {
+            self.bind_arguments(closure, arg_count, names)?
+        };
+        if self.frames.len() == FRAMES_MAX {
+            return Err("Stack overflow.".to_string());
+        }
+        let slots = self.stack.len() - self.function(closure).slot_count() - 1;
+        self.frames.push(CallFrame {
+            closure,
+            module,
+            ip: 0,
+            slots,
+            missing,
+            returns: Returns::Anything,
+        });
+        Ok(())
+    }
+
+    // Rearranges the arguments into one value per parameter slot: named
+    // arguments move to the position of their parameter, left out ones
+    // become placeholders for their default and extra ones are collected
+    // into the rest list. Returns which parameters were left out.
+    fn bind_arguments(
+        &mut self,
+        closure: ObjRef,
+        arg_count: u8,
+        names: &[String],
+    ) -> Result<Vec<bool>, String> {
+        let function = self.function(closure);
+        let (arity, variadic) = (function.arity as usize, function.variadic);
+        let required = arity - function.optional as usize;
+        let base = self.stack.len() - arg_count as usize;
+        let positional = arg_count as usize - names.len();
+
+        let mut args: Vec<Option<Value>> = vec![None; arity];
+        let mut rest = Vec::new();
+        for (index, value) in self.stack[base..base + positional].iter().enumerate() {
+            match args.get_mut(index) {
+                Some(arg) => *arg = Some(*value),
+                None if variadic => rest.push(*value),
+                None => return Err(function.arity_error(positional)),
+            }
+        }
+        for (name, value) in names.iter().zip(&self.stack[base + positional..]) {
+            match function
+                .parameters
+                .iter()
+                .position(|parameter| parameter == name)
+            {
+                Some(index) if args[index].is_none() => args[index] = Some(*value),
+                Some(_) => return Err(format!("Argument '{}' was given more than once.", name)),
+                None => return Err(format!("Unexpected argument '{}'.", name)),
+            }
+        }
+        if let Some(index) = args[..required].iter().position(Option::is_none) {
+            if names.is_empty() {
+                return Err(function.arity_error(positional));
+            }
+            return Err(format!(
+                "Missing argument '{}'.",
+                function.parameters[index]
+            ));
+        }
+
+        // The arguments are still on the stack while the rest list is
+        // allocated.
+        let rest = variadic.then(|| Value::Obj(self.alloc(Obj::List(rest))));
+        let missing: Vec<bool> = args.iter().map(Option::is_none).collect();
+        self.stack.truncate(base);
+        self.stack
+            .extend(args.into_iter().map(|arg| arg.unwrap_or(Value::Nil)));
+        self.stack.extend(rest);
+        Ok(if missing.contains(&true) {
+            missing
+        } else {
+            Vec::new()
+        })
+    }
+
+    // Calls a method of an instance or a static method of a class. A field
+    // holding a function is called like a method but without a receiver,
+    // and so is what a getter returns, once the getter has run on a copy of
+    // the receiver pushed above the arguments.
+    fn invoke_member(&mut self, name: ObjRef, arg_count: u8) -> Result<(), String> {
+        let base = self.stack.len() - arg_count as usize - 1;
+        let Value::Obj(receiver) = self.stack[base] else {
+            unreachable!("Expected an instance or a class");
+        };
+        if let Some(getter) = self.accessor(&self.stack[base], name, false) {
+            self.stack.push(self.stack[base]);
+            return self.call_special(getter, 0, Returns::Callee(arg_count));
+        }
+        let method = match self.heap.get(receiver) {
+            Obj::Instance(instance) => match instance.fields.get(&name) {
+                Some(field) => {
+                    let field = *field;
+                    self.stack[base] = field;
+                    return self.call_value(field, arg_count, &[]);
+                }
+                None => self.class(instance.class).methods.get(&name).copied(),
+            },
+            Obj::Class(class) => class.static_methods.get(&name).copied(),
+            _ => unreachable!("Expected an instance or a class"),
+        };
+        match method {
+            Some(method) => self.call_closure(method, arg_count, &[]),
+            None => Err(self.undefined_property(name)),
+        }
+    }
+
+    // The method overloading the operator when its left operand, or only
+    // operand, is an instance. Otherwise an instance on the right of a binary
+    // operator is asked for its reflected method, and the flag tells that
+    // the operands need swapping. Instances that don't overload equality or
+    // conversion to a string fall back to the usual behavior, any other
+    // operator is an error.
+    fn overload(&mut self, opcode: OpCode) -> Option<Result<(ObjRef, bool), String>> {
+        let (name, arg_count) = operator_method(opcode)?;
+        let (class, name, reflected) = match self.instance_class(self.peek(arg_count as usize)) {
+            Some(class) => (class, name, false),
+            None if arg_count == 1 => {
+                let class = self.instance_class(self.peek(0))?;
+                (class, reflected_method(opcode)?, true)
+            }
+            None => return None,
+        };
+        match (self.special_method(class, name), opcode) {
+            (Some(method), _) => Some(Ok((method, reflected))),
+            (None, OpCode::Equal | OpCode::Print | OpCode::ToString) => None,
+            (None, _) => {
+                let class = self.heap.format_value(&Value::Obj(self.class(class).name));
+                Some(Err(format!("'{}' has no '{}' method.", class, name)))
+            }
+        }
+    }
+
+    // The other operands are the arguments, so the result takes the place of
+    // the operands on the stack. A reflected method gets them swapped.
+    // `print` and string conversion run again on the string `__str__`
+    // returns.
+    fn call_operator(
+        &mut self,
+        opcode: OpCode,
+        method: ObjRef,
+        reflected: bool,
+    ) -> Result<(), String> {
+        let Some((_, arg_count)) = operator_method(opcode) else {
+            unreachable!("Expected an operator");
+        };
+        if reflected {
+            let len = self.stack.len();
+            self.stack.swap(len - 1, len - 2);
+        }
+        // An index assignment evaluates to the assigned value, whatever the
+        // method returns, so a copy goes below the instance.
+        if matches!(opcode, OpCode::SetIndex) {
+            let value = *self.peek(0);
+            let base = self.stack.len() - 3;
+            self.stack.insert(base, value);
+            return self.call_special(method, arg_count, Returns::Nothing);
+        }
+        if !matches!(opcode, OpCode::Print | OpCode::ToString) {
+            return self.call_closure(method, arg_count, &[]);
+        }
+        self.frame_mut().ip -= 1;
+        self.call_special(method, 0, Returns::String)
+    }
+
+    fn call_special(
+        &mut self,
+        method: ObjRef,
+        arg_count: u8,
+        returns: Returns,
+    ) -> Result<(), String> {
+        self.call_closure(method, arg_count, &[])?;
+        self.frame_mut().returns = returns;
+        Ok(())
+    }
+
+    // The method a class defines under a name the VM looks for by itself.
+    fn special_method(&mut self, class: ObjRef, name: &str) -> Option<ObjRef> {
+        let name = self.heap.find_string(name)?;
+        self.class(class).methods.get(&name).copied()
+    }
+
+    // The class of the value when it is an instance.
+    fn instance_class(&self, value: &Value) -> Option<ObjRef> {
+        match value {
+            Value::Obj(handle) => match self.heap.get(*handle) {
+                Obj::Instance(instance) => Some(instance.class),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Built-in collections and instances that are their own iterator.
+    fn is_iterable(&mut self, value: &Value) -> bool {
+        if let Some(class) = self.instance_class(value) {
+            return self.special_method(class, "next").is_some();
+        }
+        match value {
+            Value::Obj(handle) => matches!(
+                self.heap.get(*handle),
+                Obj::List(_) | Obj::Map(_) | Obj::String(_) | Obj::Range(..)
+            ),
+            _ => false,
+        }
+    }
+
+    fn has_members(&self, value: &Value) -> bool {
+        match value {
+            Value::Obj(handle) => {
+                matches!(self.heap.get(*handle), Obj::Instance(_) | Obj::Class(_))
+            }
+            _ => false,
+        }
+    }
+
+    fn is_module(&self, value: &Value) -> bool {
+        match value {
+            Value::Obj(handle) => matches!(self.heap.get(*handle), Obj::Module(_)),
+            _ => false,
+        }
+    }
+
+    // Closures capturing the same local share one upvalue.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let heap = &self.heap;
+        let existing = self.open_upvalues.iter().copied().find(|upvalue| {
+            matches!(heap.get(*upvalue), Obj::Upvalue(Upvalue::Open(open)) if *open == slot)
+        });
+        if let Some(upvalue) = existing {
+            return upvalue;
+        }
+        let upvalue = self.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    // Moves every local from `from` upwards that a closure captured off the
+    // stack and into its upvalue.
+    fn close_upvalues(&mut self, from: usize) {
+        let heap = &mut self.heap;
+        let stack = &self.stack;
+        self.open_upvalues
+            .retain(|upvalue| match heap.get_mut(*upvalue) {
+                Obj::Upvalue(state) => match *state {
+                    Upvalue::Open(slot) if slot >= from => {
+                        *state = Upvalue::Closed(stack[slot]);
+                        false
+                    }
+                    _ => true,
+                },
+                _ => unreachable!("Expected an upvalue"),
+            });
+    }
+
+    // Advances the iterator, returning `None` once it is exhausted. Maps
+    // produce their keys.
+    fn iterator_next(&mut self, iterator: ObjRef) -> Option<Value> {
+        let Obj::Iterator(LoxIterator { iterable, position }) = self.heap.get(iterator) else {
+            unreachable!("Expected an iterator");
+        };
+        let (iterable, position) = (*iterable, *position);
+
+        let (value, next_position) = match self.heap.get(iterable) {
+            Obj::List(items) => (*items.get(position)?, position + 1),
+            Obj::Map(map) => {
+                let (key, _) = map.iter().nth(position)?;
+                (Value::from(*key), position + 1)
+            }
+            Obj::Range(start, end) => {
+                let value = start.checked_add(position as i64)?;
+                if value >= *end {
+                    return None;
+                }
+                (Value::Int(value), position + 1)
+            }
+            Obj::String(string) => {
+                let character = string[position..].chars().next()?;
+                let character_string = character.to_string();
+                let next_position = position + character.len_utf8();
+                (Value::Obj(self.intern(character_string)), next_position)
+            }
+            _ => unreachable!("Expected an iterable"),
+        };
+
+        if let Obj::Iterator(state) = self.heap.get_mut(iterator) {
+            state.position = next_position;
+        }
+        Some(value)
+    }
+
+    fn get_index(&self, target: &Value, index: &Value) -> Result<Value, String> {
+        match target {
+            Value::Obj(handle) => match self.heap.get(*handle) {
+                Obj::List(items) => Ok(items[list_index(index, items.len())?]),
+                Obj::Map(map) => {
+                    let key = self.map_key(index)?;
+                    map.get(&key).ok_or_else(|| self.undefined_key(index))
+                }
+                _ => Err("Only lists and maps can be indexed.".to_string()),
+            },
+            _ => Err("Only lists and maps can be indexed.".to_string()),
+        }
+    }
+
+    fn set_index(&mut self, target: &Value, index: &Value, value: Value) -> Result<(), String> {
+        let Value::Obj(handle) = *target else {
+            return Err("Only lists and maps can be indexed.".to_string());
+        };
+        match self.heap.get(handle) {
+            Obj::List(items) => {
+                let index = list_index(index, items.len())?;
+                self.list_items_mut(handle)[index] = value;
+            }
+            Obj::Map(_) => {
+                let key = self.insert_key(index)?;
+                self.map_entries_mut(handle).insert(key, value);
+            }
+            _ => return Err("Only lists and maps can be indexed.".to_string()),
+        }
+        Ok(())
+    }
+
+    // Integral floats too large for an int share the key of the equal big
+    // int. Looking one up needs no new big int: when none is interned, no
+    // map holds that key.
+    fn map_key(&self, value: &Value) -> Result<MapKey, &'static str> {
+        match value {
+            Value::Int(int) => Ok(MapKey::Int(*int)),
+            Value::Number(number) => Ok(match MapKey::number(*number) {
+                MapKey::Number(_) if number.fract() == 0.0 => BigInt::from_f64(*number)
+                    .and_then(|int| self.heap.find_big_int(&int))
+                    .map_or(MapKey::number(*number), MapKey::BigInt),
+                key => key,
+            }),
+            Value::Boolean(boolean) => Ok(MapKey::Boolean(*boolean)),
+            Value::Nil => Ok(MapKey::Nil),
+            Value::Obj(handle) => match self.heap.get(*handle) {
+                Obj::String(_) => Ok(MapKey::String(*handle)),
+                Obj::BigInt(_) => Ok(MapKey::BigInt(*handle)),
+                _ => Err("Map keys must be numbers, strings, booleans or nil."),
+            },
+        }
+    }
+
+    // Like `map_key`, but interns the big int an integral float needs, for
+    // keys about to be inserted. It does not collect, as the big int is only
+    // reachable once the map holds it.
+    fn insert_key(&mut self, value: &Value) -> Result<MapKey, &'static str> {
+        let key = self.map_key(value)?;
+        match (key, value) {
+            (MapKey::Number(_), Value::Number(number)) if number.fract() == 0.0 => {
+                let int = BigInt::from_f64(*number).unwrap();
+                Ok(MapKey::BigInt(self.heap.intern_big_int(int)))
+            }
+            _ => Ok(key),
+        }
+    }
+
+    fn number(&self, value: &Value) -> Option<Number> {
+        match *value {
+            Value::Int(int) => Some(Number::Int(int)),
+            Value::Number(number) => Some(Number::Float(number)),
+            Value::Obj(handle) => match self.heap.get(handle) {
+                Obj::BigInt(int) => Some(Number::Big(int.clone())),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Big ints that fit into an `i64` become plain ints again.
+    fn number_value(&mut self, number: Number) -> Value {
+        match number {
+            Number::Int(int) => Value::Int(int),
+            Number::Float(number) => Value::Number(number),
+            Number::Big(int) => match int.to_i64() {
+                Some(int) => Value::Int(int),
+                None => Value::Obj(self.intern_big_int(int)),
+            },
+        }
+    }
+
+    // Numbers are equal when they denote the same value, whatever their
+    // kind. Big ints are interned, so two of them are equal exactly when
+    // they share a handle.
+    fn values_equal(&self, a: &Value, b: &Value) -> bool {
+        match (self.number(a), self.number(b)) {
+            (Some(Number::Float(float)), Some(int)) | (Some(int), Some(Number::Float(float)))
+                if !matches!(int, Number::Float(_)) =>
+            {
+                float.fract() == 0.0 && BigInt::from_f64(float) == Some(int.to_big())
+            }
+            _ => a == b,
+        }
+    }
+
+    fn undefined_key(&self, key: &Value) -> String {
+        format!("Undefined key '{}'.", self.heap.format_value(key))
+    }
+
+    fn map_entries(&self, map: ObjRef) -> &LoxMap {
+        match self.heap.get(map) {
+            Obj::Map(map) => map,
+            _ => unreachable!("Expected a map"),
+        }
+    }
+
+    fn map_entries_mut(&mut self, map: ObjRef) -> &mut LoxMap {
+        match self.heap.get_mut(map) {
+            Obj::Map(map) => map,
+            _ => unreachable!("Expected a map"),
+        }
+    }
+
+    fn invoke_map_method(
+        &mut self,
+        map: ObjRef,
+        name: &Value,
+        arg_count: u8,
+    ) -> Result<Value, String> {
+        let args: Vec<Value> = self.stack[self.stack.len() - arg_count as usize..].to_vec();
+        let name = self.heap.format_value(name);
+
+        match name.as_str() {
+            "keys" => {
+                check_arity(0, &args)?;
+                let keys = self
+                    .map_entries(map)
+                    .iter()
+                    .map(|(key, _)| Value::from(*key))
+                    .collect();
+                Ok(Value::Obj(self.alloc(Obj::List(keys))))
+            }
+            "values" => {
+                check_arity(0, &args)?;
+                let values = self
+                    .map_entries(map)
+                    .iter()
+                    .map(|(_, value)| *value)
+                    .collect();
+                Ok(Value::Obj(self.alloc(Obj::List(values))))
+            }
+            "has" => {
+                check_arity(1, &args)?;
+                let key = self.map_key(&args[0])?;
+                Ok(Value::Boolean(self.map_entries(map).contains_key(&key)))
+            }
+            "remove" => {
+                check_arity(1, &args)?;
+                let key = self.map_key(&args[0])?;
+                self.map_entries_mut(map)
+                    .remove(&key)
+                    .ok_or_else(|| self.undefined_key(&args[0]))
+            }
+            _ => Err(format!("Undefined method '{}' on map.", name)),
+        }
+    }
+
+    fn list_items(&self, list: ObjRef) -> &Vec<Value> {
+        match self.heap.get(list) {
+            Obj::List(items) => items,
+            _ => unreachable!("Expected a list"),
+        }
+    }
+
+    fn list_items_mut(&mut self, list: ObjRef) -> &mut Vec<Value> {
+        match self.heap.get_mut(list) {
+            Obj::List(items) => items,
+            _ => unreachable!("Expected a list"),
+        }
+    }
+
+    fn invoke_string_method(
+        &mut self,
+        string: ObjRef,
+        name: &Value,
+        arg_count: u8,
+    ) -> Result<Value, String> {
+        let args: Vec<Value> = self.stack[self.stack.len() - arg_count as usize..].to_vec();
+        let name = self.heap.format_value(name);
+        let Obj::String(string) = self.heap.get(string) else {
+            unreachable!("Expected a string");
+        };
+
+        match name.as_str() {
+            // Decimal digits, or hex digits after `0x`, with an optional
+            // sign in front.
+            "int" => {
+                check_arity(0, &args)?;
+                let text = string.trim();
+                let (sign, digits) = match text.strip_prefix('-') {
+                    Some(rest) => ("-", rest),
+                    None => ("", text.strip_prefix('+').unwrap_or(text)),
+                };
+                let int = match digits
+                    .strip_prefix("0x")
+                    .or_else(|| digits.strip_prefix("0X"))
+                {
+                    Some(hex) => BigInt::parse(&format!("{}{}", sign, hex), 16),
+                    None => BigInt::parse(&format!("{}{}", sign, digits), 10),
+                };
+                match int {
+                    Some(int) => Ok(self.number_value(Number::Big(int))),
+                    None => Err(format!("Invalid integer '{}'.", string)),
+                }
+            }
+            _ => Err(format!("Undefined method '{}' on string.", name)),
+        }
+    }
 
-                OpCode::Return => return InterpretResult::Ok,
-                _ => println!("other"),
+    fn invoke_int_method(
+        &mut self,
+        int: &Value,
+        name: &Value,
+        arg_count: u8,
+    ) -> Result<Value, String> {
+        let args: Vec<Value> = self.stack[self.stack.len() - arg_count as usize..].to_vec();
+        let name = self.heap.format_value(name);
+        let Some(int) = self.number(int) else {
+            unreachable!("Expected an int");
+        };
+
+        match name.as_str() {
+            "hex" => {
+                check_arity(0, &args)?;
+                let int = int.to_big();
+                let hex = match int.is_negative() {
+                    true => format!("-0x{}", int.neg().to_string_radix(16)),
+                    false => format!("0x{}", int.to_string_radix(16)),
+                };
+                Ok(Value::Obj(self.intern(hex)))
             }
+            _ => Err(format!("Undefined method '{}' on int.", name)),
         }
-        InterpretResult::Ok
+    }
+
+    // Arguments are still on the stack above the receiver; `Invoke` pops
+    // them once the method has produced its result.
+    fn invoke_list_method(
+        &mut self,
+        list: ObjRef,
+        name: &Value,
+        arg_count: u8,
+    ) -> Result<Value, String> {
+        let args: Vec<Value> = self.stack[self.stack.len() - arg_count as usize..].to_vec();
+        let name = self.heap.format_value(name);
+        let len = self.list_items(list).len();
+
+        match name.as_str() {
+            "push" => {
+                check_arity(1, &args)?;
+                self.list_items_mut(list).push(args[0]);
+                Ok(Value::Nil)
+            }
+            "pop" => {
+                check_arity(0, &args)?;
+                self.list_items_mut(list)
+                    .pop()
+                    .ok_or_else(|| "Can't pop from an empty list.".to_string())
+            }
+            "len" => {
+                check_arity(0, &args)?;
+                Ok(Value::Int(len as i64))
+            }
+            "insert" => {
+                check_arity(2, &args)?;
+                // Negative positions count from the end as in indexing, while
+                // a position equal to the length appends.
+                let index = match as_float(&args[0]) {
+                    Some(number) if number < 0.0 => list_index(&args[0], len)?,
+                    _ => list_index(&args[0], len + 1)?,
+                };
+                self.list_items_mut(list).insert(index, args[1]);
+                Ok(Value::Nil)
+            }
+            "remove" => {
+                check_arity(1, &args)?;
+                let index = list_index(&args[0], len)?;
+                Ok(self.list_items_mut(list).remove(index))
+            }
+            "slice" => {
+                if args.is_empty() || args.len() > 2 {
+                    return Err(format!("Expected 1 or 2 arguments but got {}.", args.len()));
+                }
+                let start = slice_bound(&args[0], len)?;
+                let end = match args.get(1) {
+                    Some(end) => slice_bound(end, len)?,
+                    None => len,
+                };
+                let items = self.list_items(list)[start..end.max(start)].to_vec();
+                Ok(Value::Obj(self.alloc(Obj::List(items))))
+            }
+            _ => Err(format!("Undefined method '{}' on list.", name)),
+        }
+    }
+
+    fn undefined_variable(&mut self, slot: u16) -> InterpretResult {
+        let name = self
+            .heap
+            .format_value(&Value::Obj(self.globals().name(slot)));
+        self.runtime_error(&format!("Undefined variable '{}'", name))
+    }
+
+    // Throws an error object carrying the message and the line of the
+    // failing instruction.
+    fn runtime_error(&mut self, message: &str) -> InterpretResult {
+        let error = LoxError {
+            message: message.to_string(),
+            line: self.current_line(),
+        };
+        self.exception = Some(Value::Obj(self.alloc(Obj::Error(error))));
+        InterpretResult::RuntimeError
+    }
+
+    fn current_line(&self) -> usize {
+        self.chunk().get_line(self.frame().ip - 1)
     }
 
     // fn read_constant(&self, index: &u8) -> Value {
@@ -143,3 +1848,357 @@ impl VM {
     //     opcode
     // }
 }
+
+// Ints, and floats without a fractional part that fit into an `i64`.
+fn exact_integer(value: &Value) -> Option<i64> {
+    match *value {
+        Value::Int(int) => Some(int),
+        Value::Number(number)
+            if number.fract() == 0.0 && number >= i64::MIN as f64 && number < i64::MAX as f64 =>
+        {
+            Some(number as i64)
+        }
+        _ => None,
+    }
+}
+
+// A numeric operand. Arithmetic happens on these and the result is turned
+// back into a `Value` by `VM::number_value`.
+enum Number {
+    Int(i64),
+    Big(BigInt),
+    Float(f64),
+}
+
+impl Number {
+    fn to_float(&self) -> f64 {
+        match self {
+            Number::Int(int) => *int as f64,
+            Number::Big(int) => int.to_f64(),
+            Number::Float(number) => *number,
+        }
+    }
+
+    // Ints, and floats without a fractional part as ints.
+    fn into_integer(self) -> Option<Number> {
+        match self {
+            Number::Float(number) if number.fract() == 0.0 => {
+                let int = BigInt::from_f64(number)?;
+                Some(match int.to_i64() {
+                    Some(int) => Number::Int(int),
+                    None => Number::Big(int),
+                })
+            }
+            Number::Float(_) => None,
+            int => Some(int),
+        }
+    }
+
+    // Only called on ints.
+    fn to_big(&self) -> BigInt {
+        match self {
+            Number::Int(int) => BigInt::from_i64(*int),
+            Number::Big(int) => int.clone(),
+            Number::Float(_) => unreachable!("Expected an int"),
+        }
+    }
+}
+
+// Either kind of number as a float.
+fn as_float(value: &Value) -> Option<f64> {
+    match *value {
+        Value::Int(int) => Some(int as f64),
+        Value::Number(number) => Some(number),
+        _ => None,
+    }
+}
+
+// The special method instances overload an operator with, and how many
+// operands besides the instance it takes as arguments.
+fn operator_method(opcode: OpCode) -> Option<(&'static str, u8)> {
+    Some(match opcode {
+        OpCode::Add => ("__add__", 1),
+        OpCode::Subtract => ("__sub__", 1),
+        OpCode::Multiply => ("__mul__", 1),
+        OpCode::Divide => ("__div__", 1),
+        OpCode::IntDivide => ("__floordiv__", 1),
+        OpCode::Modulo => ("__mod__", 1),
+        OpCode::Power => ("__pow__", 1),
+        OpCode::Negate => ("__neg__", 0),
+        OpCode::Equal => ("__eq__", 1),
+        OpCode::Less => ("__lt__", 1),
+        OpCode::Greater => ("__gt__", 1),
+        OpCode::GetIndex => ("__index__", 1),
+        OpCode::SetIndex => ("__setindex__", 2),
+        OpCode::Print | OpCode::ToString => ("__str__", 0),
+        _ => return None,
+    })
+}
+
+// The method an instance on the right of a binary operator overloads it
+// with when the left operand is not an instance.
+fn reflected_method(opcode: OpCode) -> Option<&'static str> {
+    Some(match opcode {
+        OpCode::Add => "__radd__",
+        OpCode::Subtract => "__rsub__",
+        OpCode::Multiply => "__rmul__",
+        OpCode::Divide => "__rdiv__",
+        OpCode::IntDivide => "__rfloordiv__",
+        OpCode::Modulo => "__rmod__",
+        OpCode::Power => "__rpow__",
+        OpCode::Equal => "__eq__",
+        OpCode::Less => "__gt__",
+        OpCode::Greater => "__lt__",
+        _ => return None,
+    })
+}
+
+// Arithmetic on ints stays exact, moving to big ints when a result does not
+// fit into an `i64`, except for `/` which always produces a float. As soon as
+// one operand is a float the other one is promoted, and `//` turns the
+// result back into an int.
+fn arithmetic(opcode: OpCode, a: Number, b: Number) -> Result<Number, &'static str> {
+    match (&a, &b) {
+        (Number::Float(_), _) | (_, Number::Float(_)) => {}
+        _ if matches!(opcode, OpCode::Divide) => {}
+        (Number::Int(x), Number::Int(y)) => {
+            if let Some(result) = int_arithmetic(opcode, *x, *y)? {
+                return Ok(Number::Int(result));
+            }
+            return big_arithmetic(opcode, &a.to_big(), &b.to_big());
+        }
+        _ => return big_arithmetic(opcode, &a.to_big(), &b.to_big()),
+    }
+
+    let (a, b) = (a.to_float(), b.to_float());
+    // `/` follows IEEE 754 and yields infinities, but there is no sensible
+    // integer quotient or remainder for zero.
+    if b == 0.0 && matches!(opcode, OpCode::IntDivide | OpCode::Modulo) {
+        return Err("Division by zero.");
+    }
+    let result = match opcode {
+        OpCode::Add => a + b,
+        OpCode::Subtract => a - b,
+        OpCode::Multiply => a * b,
+        OpCode::Divide => a / b,
+        OpCode::IntDivide => {
+            return BigInt::from_f64((a / b).floor())
+                .map(Number::Big)
+                .ok_or("Integer division result is not finite.");
+        }
+        OpCode::Modulo => floored_remainder(a, b),
+        _ => a.powf(b),
+    };
+    Ok(Number::Float(result))
+}
+
+// Returns `None` when the result overflows.
+fn int_arithmetic(opcode: OpCode, a: i64, b: i64) -> Result<Option<i64>, &'static str> {
+    if b == 0 && matches!(opcode, OpCode::IntDivide | OpCode::Modulo) {
+        return Err("Division by zero.");
+    }
+    let result = match opcode {
+        OpCode::Add => a.checked_add(b),
+        OpCode::Subtract => a.checked_sub(b),
+        OpCode::Multiply => a.checked_mul(b),
+        OpCode::IntDivide => a.checked_div(b).map(|quotient| {
+            if a % b != 0 && (a < 0) != (b < 0) {
+                quotient - 1
+            } else {
+                quotient
+            }
+        }),
+        OpCode::Modulo => a.checked_rem(b).map(|remainder| {
+            if remainder != 0 && (remainder < 0) != (b < 0) {
+                remainder + b
+            } else {
+                remainder
+            }
+        }),
+        // Negative exponents are left to `big_arithmetic`.
+        _ => u32::try_from(b)
+            .ok()
+            .and_then(|exponent| a.checked_pow(exponent)),
+    };
+    Ok(result)
+}
+
+fn big_arithmetic(opcode: OpCode, a: &BigInt, b: &BigInt) -> Result<Number, &'static str> {
+    let result = match opcode {
+        OpCode::Add => a.add(b),
+        OpCode::Subtract => a.sub(b),
+        OpCode::Multiply => a.mul(b),
+        OpCode::IntDivide | OpCode::Modulo => {
+            let (quotient, remainder) = a.div_rem(b).ok_or("Division by zero.")?;
+            if matches!(opcode, OpCode::IntDivide) {
+                quotient
+            } else {
+                remainder
+            }
+        }
+        // A negative exponent has no integer result.
+        _ if b.is_negative() => return Ok(Number::Float(a.to_f64().powf(b.to_f64()))),
+        _ => match b.to_i64().and_then(|exponent| u32::try_from(exponent).ok()) {
+            Some(exponent) => a.pow(exponent),
+            None => return Err("Exponent is too large."),
+        },
+    };
+    Ok(Number::Big(result))
+}
+
+// Operands are ints. Small ints move to big ints when a left shift
+// overflows.
+fn bitwise(opcode: OpCode, a: Number, b: Number) -> Result<Number, &'static str> {
+    if matches!(opcode, OpCode::ShiftLeft | OpCode::ShiftRight) {
+        return shift(opcode, a, b);
+    }
+    if let (Number::Int(a), Number::Int(b)) = (&a, &b) {
+        return Ok(Number::Int(match opcode {
+            OpCode::BitAnd => a & b,
+            OpCode::BitOr => a | b,
+            _ => a ^ b,
+        }));
+    }
+    let (a, b) = (a.to_big(), b.to_big());
+    Ok(Number::Big(match opcode {
+        OpCode::BitAnd => a.and(&b),
+        OpCode::BitOr => a.or(&b),
+        _ => a.xor(&b),
+    }))
+}
+
+// Right shifts by any count are fine, ending at 0 or -1, while left shifts
+// by more than `u32::MAX` bits would never fit into memory.
+fn shift(opcode: OpCode, a: Number, b: Number) -> Result<Number, &'static str> {
+    let count = match b {
+        Number::Int(count) if count < 0 => return Err("Shift count out of range."),
+        Number::Big(count) if count.is_negative() => return Err("Shift count out of range."),
+        Number::Int(count) => u32::try_from(count).ok(),
+        _ => None,
+    };
+    if matches!(opcode, OpCode::ShiftLeft) {
+        let Some(count) = count else {
+            return Err("Shift count out of range.");
+        };
+        if let Number::Int(int) = a {
+            let shifted = int
+                .checked_shl(count)
+                .filter(|shifted| shifted >> count == int);
+            if let Some(shifted) = shifted {
+                return Ok(Number::Int(shifted));
+            }
+        }
+        return Ok(Number::Big(a.to_big().shift_left(count as usize)));
+    }
+    Ok(match a {
+        Number::Int(int) => Number::Int(int >> count.unwrap_or(63).min(63)),
+        _ => Number::Big(
+            a.to_big()
+                .shift_right(count.map_or(usize::MAX, |count| count as usize)),
+        ),
+    })
+}
+
+// Ints of either size compare exactly, also against floats. NaN is neither
+// greater nor less than anything.
+fn compare_numbers(a: &Number, b: &Number) -> Ordering {
+    match (a, b) {
+        (Number::Float(a), Number::Float(b)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+        (int, Number::Float(float)) => compare_int_float(int, *float),
+        (Number::Float(float), int) => compare_int_float(int, *float).reverse(),
+        (Number::Int(a), Number::Int(b)) => a.cmp(b),
+        _ => a.to_big().cmp(&b.to_big()),
+    }
+}
+
+// Compares against the float's floor, so the int is never rounded. An int
+// equal to the floor of a float with a fractional part is less than it.
+fn compare_int_float(int: &Number, float: f64) -> Ordering {
+    if float.is_nan() {
+        return Ordering::Equal;
+    }
+    if float.is_infinite() {
+        return if float > 0.0 {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        };
+    }
+    let floor = float.floor();
+    let ordering = match *int {
+        Number::Int(_) if floor >= i64::MAX as f64 => Ordering::Less,
+        Number::Int(_) if floor < i64::MIN as f64 => Ordering::Greater,
+        Number::Int(int) => int.cmp(&(floor as i64)),
+        _ => int.to_big().cmp(&BigInt::from_f64(floor).unwrap()),
+    };
+    match ordering {
+        Ordering::Equal if floor != float => Ordering::Less,
+        ordering => ordering,
+    }
+}
+
+// Takes the sign of the divisor, so `a == b * (a // b) + a % b`.
+fn floored_remainder(a: f64, b: f64) -> f64 {
+    let remainder = a % b;
+    if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+        remainder + b
+    } else {
+        remainder
+    }
+}
+
+fn module_name(path: &Path) -> String {
+    match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => path.display().to_string(),
+    }
+}
+
+fn check_arity(expected: usize, args: &[Value]) -> Result<(), String> {
+    if args.len() != expected {
+        return Err(format!(
+            "Expected {} arguments but got {}.",
+            expected,
+            args.len()
+        ));
+    }
+    Ok(())
+}
+
+// Negative indices count from the end, so -1 is the last element.
+fn list_index(index: &Value, len: usize) -> Result<usize, &'static str> {
+    let Some(number) = as_float(index) else {
+        return Err("List index must be a number.");
+    };
+    if number.fract() != 0.0 {
+        return Err("List index must be an integer.");
+    }
+
+    let index = if number < 0.0 {
+        number + len as f64
+    } else {
+        number
+    };
+    if index < 0.0 || index >= len as f64 {
+        return Err("List index out of range.");
+    }
+    Ok(index as usize)
+}
+
+// Slice bounds follow the same negative indexing but are clamped to the list
+// instead of raising an error.
+fn slice_bound(bound: &Value, len: usize) -> Result<usize, String> {
+    let Some(number) = as_float(bound) else {
+        return Err("Slice bounds must be numbers.".to_string());
+    };
+    if number.fract() != 0.0 {
+        return Err("Slice bounds must be integers.".to_string());
+    }
+
+    let bound = if number < 0.0 {
+        number + len as f64
+    } else {
+        number
+    };
+    Ok(bound.clamp(0.0, len as f64) as usize)
+}
@@ -0,0 +1,1253 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// What a script printed and the interpreter's exit code.
+struct Output {
+    stdout: String,
+    stderr: String,
+    status: i32,
+}
+
+fn run(source: &str) -> Output {
+    run_with_flags(source, &[])
+}
+
+// Runs the source as a script with the interpreter binary. Each script gets
+// its own file, so tests can run in parallel.
+fn run_with_flags(source: &str, flags: &[&str]) -> Output {
+    let path = temp_path("lox");
+    fs::write(&path, source).expect("Failed to write script");
+    let output = run_path(&path, flags);
+    fs::remove_file(&path).ok();
+    output
+}
+
+// Writes the files into a directory of their own and runs the first one,
+// so scripts can import the others by relative paths.
+fn run_modules(files: &[(&str, &str)]) -> Output {
+    let dir = temp_path("modules");
+    for (name, source) in files {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).expect("Failed to create directory");
+        fs::write(&path, source).expect("Failed to write module");
+    }
+    let output = run_path(&dir.join(files[0].0), &[]);
+    fs::remove_dir_all(&dir).ok();
+    output
+}
+
+fn temp_path(extension: &str) -> PathBuf {
+    static NEXT_PATH: AtomicUsize = AtomicUsize::new(0);
+    env::temp_dir().join(format!(
+        "rlox-test-{}-{}.{}",
+        std::process::id(),
+        NEXT_PATH.fetch_add(1, Ordering::Relaxed),
+        extension
+    ))
+}
+
+fn run_path(path: &Path, flags: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(flags)
+        .arg(path)
+        .output()
+        .expect("Failed to run interpreter");
+    Output {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        status: output.status.code().unwrap_or(-1),
+    }
+}
+
+// Expects the script to run to completion and print exactly these lines.
+fn assert_prints(source: &str, expected: &[&str]) {
+    let output = run(source);
+    assert_eq!(output.status, 0, "stderr: {}", output.stderr);
+    let lines: Vec<&str> = output.stdout.lines().collect();
+    assert_eq!(lines, expected);
+}
+
+fn assert_compile_error(source: &str, message: &str) {
+    let output = run(source);
+    assert_eq!(output.status, 65, "stderr: {}", output.stderr);
+    assert!(output.stderr.contains(message), "stderr: {}", output.stderr);
+}
+
+fn assert_runtime_error(source: &str, message: &str) {
+    let output = run(source);
+    assert_eq!(output.status, 70, "stderr: {}", output.stderr);
+    assert!(output.stderr.contains(message), "stderr: {}", output.stderr);
+}
+
+#[test]
+fn unterminated_interpolation_is_a_compile_error() {
+    assert_compile_error("print \"abc${1\";\n", "Unterminated string.");
+    assert_compile_error(
+        "print \"a${1 2}\";\n",
+        "Expect end of string interpolation.",
+    );
+    assert_prints("print \"a${1}b${2}c\";\n", &["a1b2c"]);
+}
+
+#[test]
+fn chunks_hold_more_than_256_constants() {
+    let numbers: String = (0..300).map(|i| format!("print {};\n", i)).collect();
+    let output = run(&numbers);
+    assert_eq!(output.status, 0, "stderr: {}", output.stderr);
+    assert_eq!(output.stdout.lines().last(), Some("299"));
+
+    // Names still have to be among the first 256 constants, which repeated
+    // literals don't use up.
+    let access = "class A { init() { this.x = \"x\"; } }\nprint A().x;\n";
+    assert_compile_error(&(numbers + access), "Too many constants in one chunk.");
+    let repeated: String = (0..300).map(|_| "print 7; print 1.5;\n").collect();
+    let output = run(&(repeated + access));
+    assert_eq!(output.status, 0, "stderr: {}", output.stderr);
+    assert_eq!(output.stdout.lines().last(), Some("x"));
+}
+
+#[test]
+fn for_loops_iterate_over_instances() {
+    assert_prints(
+        r#"
+class Countdown {
+  init(n) { this.n = n; }
+  next() {
+    if (this.n == 0) return nil;
+    this.n -= 1;
+    return this.n + 1;
+  }
+}
+class Bag {
+  init() { this.items = ["a", "b"]; }
+  iter() => this.items
+}
+class Repeat {
+  init(n) { this.n = n; }
+  iter() => Countdown(this.n)
+}
+for (var x in Countdown(3)) print x;
+for (var x in Bag()) print x;
+for (var x in Repeat(4)) {
+  if (x == 3) continue;
+  if (x == 1) break;
+  print x;
+}
+"#,
+        &["3", "2", "1", "a", "b", "4", "2"],
+    );
+    assert_prints(
+        r#"
+class Empty {}
+class Itself { iter() => this }
+try { for (var x in Empty()) print x; } catch (e) { print e.message; }
+try { for (var x in Itself()) print x; } catch (e) { print e.message; }
+"#,
+        &[
+            "'Empty' has no 'iter' or 'next' method.",
+            "'iter' must return an iterable.",
+        ],
+    );
+}
+
+#[test]
+fn return_break_and_continue_run_finally_blocks() {
+    assert_prints(
+        r#"
+fun f() {
+  try {
+    var local = "kept";
+    return local;
+  } finally {
+    print "finally";
+  }
+}
+print f();
+for (var i in 0..3) {
+  try {
+    if (i == 0) continue;
+    if (i == 2) break;
+    print i;
+  } finally {
+    print "after ${i}";
+  }
+}
+fun g() {
+  try {
+    try { return "inner"; } finally { print "first"; }
+  } catch (e) {
+    print "unreachable";
+  } finally {
+    print "second";
+  }
+}
+print g();
+fun h() {
+  try { return 1; } finally { throw "from finally"; }
+}
+try { h(); } catch (e) { print e; }
+"#,
+        &[
+            "finally",
+            "kept",
+            "after 0",
+            "1",
+            "after 1",
+            "after 2",
+            "first",
+            "second",
+            "inner",
+            "from finally",
+        ],
+    );
+}
+
+#[test]
+fn shifts_and_bitwise_operators_handle_big_ints() {
+    assert_prints(
+        r#"
+print 1 << 63;
+print 1 << 64;
+print (1 << 70) >> 70;
+print -5 >> 1000;
+print (2 ** 70) & 1;
+print ((2 ** 70) + 5) & 7;
+print -(2 ** 70) & 255;
+print (2 ** 70) | 1;
+print ~(2 ** 70);
+try { print 1 << -1; } catch (e) { print e.message; }
+try { print 1 << (2 ** 40); } catch (e) { print e.message; }
+"#,
+        &[
+            "9223372036854775808",
+            "18446744073709551616",
+            "1",
+            "-1",
+            "0",
+            "5",
+            "0",
+            "1180591620717411303425",
+            "-1180591620717411303425",
+            "Shift count out of range.",
+            "Shift count out of range.",
+        ],
+    );
+}
+
+#[test]
+fn ints_compare_exactly_with_floats() {
+    assert_prints(
+        r#"
+print 9007199254740993 > 9007199254740992.0;
+print 9007199254740992.0 < 9007199254740993;
+print 9007199254740992 > 9007199254740992.0;
+print -3 < -2.5;
+print 9223372036854775807 < 9223372036854775808.0;
+"#,
+        &["true", "true", "false", "true", "true"],
+    );
+}
+
+#[test]
+fn big_ints_compare_and_hash_like_equal_floats() {
+    assert_prints(
+        r#"
+var big = 2 ** 70;
+print big == 2.0 ** 70;
+print big > 2.0 ** 70;
+print big + 1 > 2.0 ** 70;
+print 2.0 ** 70 < big + 1;
+print -(2 ** 80) < -1.5;
+var m = {2.0 ** 70: "float"};
+print m[big];
+m[2 ** 71] = "big";
+print m[2.0 ** 71];
+print m.has(2.0 ** 72);
+"#,
+        &[
+            "true", "false", "true", "true", "true", "float", "big", "false",
+        ],
+    );
+}
+
+#[test]
+fn operators_dispatch_on_instances_on_the_right() {
+    assert_prints(
+        r#"
+class V {
+  init(x) { this.x = x; }
+  __radd__(o) => o * 100 + this.x
+  __lt__(o) => this.x < o
+  __gt__(o) => this.x > o
+  __eq__(o) => this.x == o
+}
+var v = V(5);
+print 1 + v;
+print 3 < v;
+print 7 > v;
+print 5 == v;
+class P {}
+print 1 == P();
+try { print 1 * v; } catch (e) { print e.message; }
+try { print 1 < P(); } catch (e) { print e.message; }
+"#,
+        &[
+            "105",
+            "true",
+            "true",
+            "true",
+            "false",
+            "'V' has no '__rmul__' method.",
+            "'P' has no '__gt__' method.",
+        ],
+    );
+}
+
+#[test]
+fn prefix_increment_needs_a_variable_property_or_index() {
+    assert_compile_error(
+        "var a = nil; var b = 5; ++(a or b); print b;\n",
+        "Invalid assignment target.",
+    );
+    assert_compile_error("var b = 5; ++(b);\n", "Invalid assignment target.");
+    assert_compile_error("print ++1;\n", "Invalid assignment target.");
+    assert_compile_error("var b = 1; print --b();\n", "Invalid assignment target.");
+    assert_prints(
+        r#"
+var b = 5;
+print ++b;
+print --b;
+var l = [1];
+print ++l[0];
+class A { init() { this.x = 1; print ++this.x; } }
+A();
+"#,
+        &["6", "5", "2", "2"],
+    );
+}
+
+#[test]
+fn large_integer_literals_become_big_ints() {
+    assert_prints(
+        r#"
+print 99999999999999999999;
+print 99999999999999999999 + 1;
+print 9223372036854775808 - 1;
+print 12n;
+"#,
+        &[
+            "99999999999999999999",
+            "100000000000000000000",
+            "9223372036854775807",
+            "12",
+        ],
+    );
+}
+
+#[test]
+fn calling_a_getter_calls_what_it_returns() {
+    assert_prints(
+        r#"
+class A {
+  init() { this.n = 3; }
+  get f { return () => "called"; }
+  get add { return (a, b) => a + b + this.n; }
+  get bad { return 1; }
+}
+var a = A();
+print a.f();
+print a.add(1, 2);
+try { a.bad(); } catch (e) { print e.message; }
+"#,
+        &["called", "6", "Can only call functions and classes."],
+    );
+}
+
+#[test]
+fn debug_output_reports_interning_on_every_exit() {
+    for (source, status) in [
+        ("print \"a\" + \"b\";\n", 0),
+        ("var a = \"x\"; print a + nil;\n", 70),
+        ("print ;\n", 65),
+    ] {
+        let output = run_with_flags(source, &["--debug"]);
+        assert_eq!(output.status, status, "stderr: {}", output.stderr);
+        assert!(
+            output
+                .stderr
+                .contains("== string interning ==\nlive strings: "),
+            "stderr: {}",
+            output.stderr
+        );
+    }
+}
+
+#[test]
+fn index_assignment_dispatches_to_setindex() {
+    assert_prints(
+        r#"
+class Grid {
+  init() { this.cells = {}; }
+  __index__(i) => this.cells.has(i) ? this.cells[i] : i * 2
+  __setindex__(i, v) { this.cells[i] = v; return "ignored"; }
+}
+var a = Grid();
+print a[3];
+print a[1] = 2;
+print a[1];
+a[3] += 1;
+print a[3];
+class ReadOnly { __index__(i) => i }
+try { ReadOnly()[1] = 2; } catch (e) { print e.message; }
+"#,
+        &[
+            "6",
+            "2",
+            "2",
+            "7",
+            "'ReadOnly' has no '__setindex__' method.",
+        ],
+    );
+}
+
+#[test]
+fn multi_line_and_raw_strings() {
+    assert_prints(
+        r#"
+var text = """
+    first
+      indented
+    last
+    """;
+print text;
+print r"no \n escape";
+print r"\d+";
+print """one line""";
+"#,
+        &[
+            "first",
+            "  indented",
+            "last",
+            "no \\n escape",
+            "\\d+",
+            "one line",
+        ],
+    );
+    assert_compile_error("var a = \"\"\"\nunterminated\n", "Unterminated string.");
+    // Lines keep counting inside a multi-line string.
+    let output = run("var s = \"\"\"\n  a\n  b\n  \"\"\";\nprint nope;\n");
+    assert_eq!(output.status, 70);
+    assert!(
+        output.stderr.contains("[line 5]"),
+        "stderr: {}",
+        output.stderr
+    );
+}
+
+#[test]
+fn string_interpolation() {
+    assert_prints(
+        r#"
+var name = "Ada";
+var age = 36;
+print "Hello ${name}, you are ${age + 1}";
+print "nested ${"inner ${name}"} done";
+print "${nil} ${true} ${1.5} ${[1, "a"]} ${{"k": 2}}";
+print "${1}${2}";
+print "braces ${ {"a": 1}["a"] }";
+"#,
+        &[
+            "Hello Ada, you are 37",
+            "nested inner Ada done",
+            "nil true 1.5 [1, a] {k: 2}",
+            "12",
+            "braces 1",
+        ],
+    );
+}
+
+#[test]
+fn objects_survive_a_collection_on_every_allocation() {
+    let source = r#"
+var keep = [];
+for (var i in 0..200) {
+  var s = "item" + "${i}";
+  keep.push([s, {"k": s}]);
+}
+fun make(n) { var l = []; for (var i in 0..n) l.push("${i}"); return () => l; }
+var f = make(50);
+print keep.len();
+print keep[199][0];
+print keep[150][1]["k"];
+print f()[49];
+"#;
+    let output = run_with_flags(source, &["--stress-gc"]);
+    assert_eq!(output.status, 0, "stderr: {}", output.stderr);
+    let lines: Vec<&str> = output.stdout.lines().collect();
+    assert_eq!(lines, ["200", "item199", "item150", "49"]);
+}
+
+#[test]
+fn equal_strings_are_interchangeable() {
+    assert_prints(
+        r#"
+var a = "abc";
+var b = "ab" + "c";
+print a == b;
+var m = {a: 1};
+print m[b];
+print "x" != "y";
+"#,
+        &["true", "1", "true"],
+    );
+}
+
+#[test]
+fn globals_resolve_before_they_are_defined() {
+    assert_prints(
+        r#"
+fun useLater() { return later; }
+var later = "defined after use";
+print useLater();
+var g = 1;
+g = g + 1;
+print g;
+"#,
+        &["defined after use", "2"],
+    );
+    assert_runtime_error("print missing;\n", "Undefined variable 'missing'");
+    assert_runtime_error(
+        "undefinedGlobal = 3;\n",
+        "Undefined variable 'undefinedGlobal'",
+    );
+}
+
+#[test]
+fn lists() {
+    assert_prints(
+        r#"
+var xs = [1, 2, 3];
+print xs[0];
+print xs[-1];
+xs[1] = 20;
+xs.push(4);
+print xs;
+print xs.pop();
+print xs.len();
+xs.insert(0, 0);
+xs.insert(-1, 9);
+print xs;
+print xs.remove(1);
+print xs.slice(1);
+print xs.slice(0, 2);
+"#,
+        &[
+            "1",
+            "3",
+            "[1, 20, 3, 4]",
+            "4",
+            "3",
+            "[0, 1, 20, 9, 3]",
+            "1",
+            "[20, 9, 3]",
+            "[0, 20]",
+        ],
+    );
+    assert_runtime_error("var xs = [1];\nprint xs[5];\n", "List index out of range.");
+    assert_runtime_error("[].pop();\n", "Can't pop from an empty list.");
+}
+
+#[test]
+fn maps() {
+    assert_prints(
+        r#"
+var m = {"b": 1, "a": 2, 3: "three", true: "yes", nil: "none"};
+print m["a"];
+print m[3.0];
+print m[true];
+print m[nil];
+m["c"] = 3;
+print m.keys();
+print m.values();
+print m.has("b");
+print m.remove("b");
+print m.has("b");
+print m;
+{
+  var inner = 1;
+  print inner;
+}
+"#,
+        &[
+            "2",
+            "three",
+            "yes",
+            "none",
+            "[b, a, 3, true, nil, c]",
+            "[1, 2, three, yes, none, 3]",
+            "true",
+            "1",
+            "false",
+            "{a: 2, 3: three, true: yes, nil: none, c: 3}",
+            "1",
+        ],
+    );
+    assert_runtime_error(
+        "var m = {\"a\": 1};\nprint m[\"z\"];\n",
+        "Undefined key 'z'.",
+    );
+    assert_runtime_error(
+        "var m = {[1]: 1};\n",
+        "Map keys must be numbers, strings, booleans or nil.",
+    );
+}
+
+#[test]
+fn for_loops_iterate_over_built_in_values() {
+    assert_prints(
+        r#"
+for (var x in [1, 2]) print x;
+for (var c in "hé!") print c;
+for (var i in 3..6) print i;
+for (var k in {"x": 1, "y": 2}) print k;
+"#,
+        &["1", "2", "h", "é", "!", "3", "4", "5", "x", "y"],
+    );
+    assert_runtime_error(
+        "for (var x in 5) print x;\n",
+        "Can only iterate over lists, maps, strings, ranges and instances.",
+    );
+}
+
+#[test]
+fn break_and_continue() {
+    assert_prints(
+        r#"
+for (var i in 0..10) {
+  var inside = i * 2;
+  if (i == 1) continue;
+  if (i == 4) break;
+  print inside;
+}
+var n = 0;
+while (true) {
+  n += 1;
+  for (var j in 0..3) {
+    if (j == 1) break;
+    print "${n}:${j}";
+  }
+  if (n == 2) break;
+}
+var k = 0;
+while (k < 5) {
+  k += 1;
+  if (k % 2 == 0) continue;
+  print k;
+}
+"#,
+        &["0", "4", "6", "1:0", "2:0", "1", "3", "5"],
+    );
+    assert_compile_error("break;\n", "Can't use 'break' outside of a loop.");
+    assert_compile_error(
+        "for (var i in 0..2) { fun f() { continue; } }\n",
+        "Can't use 'continue' outside of a loop.",
+    );
+}
+
+#[test]
+fn exceptions_unwind_to_the_nearest_catch() {
+    assert_prints(
+        r#"
+fun inner() { throw "boom"; }
+fun outer() { var local = 1; inner(); print "unreached"; }
+try { outer(); } catch (e) { print "caught ${e}"; }
+try { print 1 + nil; } catch (e) { print e.message; print e.line; }
+try { print undefinedThing; } catch (e) { print e.message; }
+try { throw [1, 2]; } catch (e) { print e[1]; }
+try {
+  try { throw "inner"; } finally { print "cleanup"; }
+} catch (e) { print "outer ${e}"; }
+var x = "after";
+print x;
+"#,
+        &[
+            "caught boom",
+            "Operands must be two numbers or two strings.",
+            "5",
+            "Undefined variable 'undefinedThing'",
+            "2",
+            "cleanup",
+            "outer inner",
+            "after",
+        ],
+    );
+    assert_runtime_error("throw \"uncaught\";\n", "Uncaught exception: uncaught");
+    assert_compile_error(
+        "try { print 1; }\n",
+        "Expect 'catch' or 'finally' after 'try' block.",
+    );
+}
+
+#[test]
+fn modules_are_imported_once_by_relative_path() {
+    let util = r#"
+print "loading util";
+var greeting = "hi";
+fun greet(name) { return greeting + " " + name; }
+"#;
+    let output = run_modules(&[
+        (
+            "main.lox",
+            r#"
+import "lib/util.lox" as u;
+import "lib/util.lox" as again;
+print u.greet("Ada");
+print u.greeting;
+u.greeting = "hello";
+print again.greet("Bob");
+try { print u.missing; } catch (e) { print e.message; }
+"#,
+        ),
+        ("lib/util.lox", util),
+    ]);
+    assert_eq!(output.status, 0, "stderr: {}", output.stderr);
+    let lines: Vec<&str> = output.stdout.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "loading util",
+            "hi Ada",
+            "hi",
+            "hello Bob",
+            "Undefined property 'missing'."
+        ]
+    );
+
+    let output = run_modules(&[
+        ("a.lox", "import \"b.lox\" as b;\n"),
+        ("b.lox", "import \"a.lox\" as a;\n"),
+    ]);
+    assert_eq!(output.status, 70);
+    assert!(
+        output
+            .stderr
+            .contains("Import cycle detected: a.lox -> b.lox -> a.lox."),
+        "stderr: {}",
+        output.stderr
+    );
+    assert_runtime_error(
+        "import \"does-not-exist.lox\" as n;\n",
+        "Could not find module 'does-not-exist.lox'.",
+    );
+}
+
+#[test]
+fn anonymous_functions_and_lambdas() {
+    assert_prints(
+        r#"
+var add = fun (a, b) { return a + b; };
+print add(1, 2);
+var square = (x) => x * x;
+print square(4);
+var none = () => "empty";
+print none();
+fun counter() {
+  var n = 0;
+  return () => { n += 1; return n; };
+}
+var c = counter();
+c();
+print c();
+fun apply(f, v) { return f(v); }
+print apply((x) => x + 1, 41);
+print (fun (s) { return s + "!"; })("hey");
+"#,
+        &["3", "16", "empty", "2", "42", "hey!"],
+    );
+}
+
+#[test]
+fn conditional_and_coalescing_operators() {
+    assert_prints(
+        r#"
+print true ? "yes" : "no";
+print false ? "yes" : nil ?? "fallback";
+print 1 < 2 ? "a" : 3 < 4 ? "b" : "c";
+print false ? 1 : false ? 2 : 3;
+print nil ?? nil ?? "last";
+print false ?? "not nil";
+fun boom() { print "evaluated"; return 1; }
+print true ? "skip" : boom();
+print "set" ?? boom();
+"#,
+        &["yes", "fallback", "a", "3", "last", "false", "skip", "set"],
+    );
+}
+
+#[test]
+fn compound_assignment_and_postfix_steps() {
+    assert_prints(
+        r#"
+var a = 10;
+a += 5; print a;
+a -= 3; print a;
+a *= 2; print a;
+a /= 4; print a;
+a %= 4; print a;
+var s = "ab"; s += "c"; print s;
+var i = 1;
+print i++;
+print i;
+print i--;
+fun f() { var l = 0; l += 2; l++; return l; }
+print f();
+class P { init() { this.v = 1; } }
+var p = P();
+p.v += 10; print p.v;
+print p.v++; print p.v;
+var xs = [1, 2];
+xs[0] += 5; xs[1]++; print xs;
+"#,
+        &[
+            "15", "12", "24", "6.0", "2.0", "abc", "1", "2", "2", "3", "11", "11", "12", "[6, 3]",
+        ],
+    );
+    assert_compile_error(
+        "var a = 1; var b = 2; a + b += 3;\n",
+        "Invalid assignment target.",
+    );
+    assert_compile_error("1++;\n", "Invalid assignment target.");
+}
+
+#[test]
+fn assignment_needs_an_assignable_target() {
+    assert_prints(
+        "var a = 1; var b = 2; var c = 3;\na = b = c;\nprint a;\n",
+        &["3"],
+    );
+    for source in [
+        "var a = 1; var b = 2; var c = 3; a + b = c;\n",
+        "var a = 1; var b = 2; !a = b;\n",
+        "var a = [1]; a[0] + 1 = 2;\n",
+    ] {
+        assert_compile_error(source, "Invalid assignment target.");
+    }
+}
+
+#[test]
+fn modulo_exponent_and_integer_division() {
+    assert_prints(
+        r#"
+print 7 % 3;
+print -7 % 3;
+print 7 % -3;
+print 7.5 % 2;
+print 2 ** 10;
+print 2 ** 3 ** 2;
+print -2 ** 2;
+print 2 ** -1;
+print 7 // 2;
+print -7 // 2;
+print 1 / 2;
+"#,
+        &[
+            "1", "2", "-2", "1.5", "1024", "512", "-4", "0.5", "3", "-4", "0.5",
+        ],
+    );
+    assert_runtime_error("print 1 % 0;\n", "Division by zero.");
+    assert_runtime_error("print 1.5 // 0.0;\n", "Division by zero.");
+    assert_runtime_error("print \"a\" % 2;\n", "Operands must be numbers.");
+}
+
+#[test]
+fn bitwise_operators() {
+    assert_prints(
+        r#"
+print 6 & 3;
+print 6 | 3;
+print 6 ^ 3;
+print ~5;
+print 1 << 4;
+print -16 >> 2;
+print 1 | 2 & 3;
+print 1 + 1 << 1;
+print 8.0 | 1;
+"#,
+        &["2", "7", "5", "-6", "16", "-4", "3", "4", "9"],
+    );
+    assert_runtime_error("print 1.5 & 1;\n", "Operands must be integers.");
+    assert_runtime_error("print ~\"a\";\n", "Operand must be an integer.");
+}
+
+#[test]
+fn ints_and_floats_stay_apart() {
+    assert_prints(
+        r#"
+print 1;
+print 1.0;
+print 3 / 2;
+print 4 / 2;
+print 1 + 2.5;
+print 3 * 1.0;
+print 10 // 4;
+print 1 == 1.0;
+print 0.1 + 0.2;
+print -0.0;
+"#,
+        &[
+            "1",
+            "1.0",
+            "1.5",
+            "2.0",
+            "3.5",
+            "3.0",
+            "2",
+            "true",
+            "0.30000000000000004",
+            "-0.0",
+        ],
+    );
+}
+
+#[test]
+fn big_int_arithmetic_and_conversions() {
+    assert_prints(
+        r#"
+print 123n;
+var big = 9223372036854775807 + 1;
+print big;
+print big - 1;
+print big * big;
+print -big - 1;
+print (2 ** 100) // 3;
+print (2 ** 100) % 7;
+print 2 ** 64 > 2 ** 63;
+print "-123456789012345678901234567890".int();
+print "0x10".int();
+print (2 ** 64).hex();
+print (2 ** 64) / 2;
+"#,
+        &[
+            "123",
+            "9223372036854775808",
+            "9223372036854775807",
+            "85070591730234615865843651857942052864",
+            "-9223372036854775809",
+            "422550200076076467165567735125",
+            "2",
+            "true",
+            "-123456789012345678901234567890",
+            "16",
+            "0x10000000000000000",
+            "9223372036854775808.0",
+        ],
+    );
+    assert_runtime_error("print \"12x\".int();\n", "Invalid integer '12x'.");
+    assert_runtime_error("print (2 ** 70) // 0;\n", "Division by zero.");
+}
+
+#[test]
+fn match_statement_patterns() {
+    assert_prints(
+        r#"
+fun describe(v) {
+  match v {
+    1 => return "one";
+    "a" | "b" => return "letter";
+    [x, y] => return "pair ${x} ${y}";
+    [] => return "empty";
+    nil => return "nothing";
+    other => return "other ${other}";
+  }
+}
+print describe(1);
+print describe("b");
+print describe([3, 4]);
+print describe([]);
+print describe(nil);
+print describe(2.5);
+print describe([1, 2, 3]);
+match 5 {
+  _ => print "wildcard";
+}
+match 6 {
+  1 => print "no";
+}
+match [1, [2, 3]] {
+  [a, [b, c]] => { print a + b + c; }
+}
+"#,
+        &[
+            "one",
+            "letter",
+            "pair 3 4",
+            "empty",
+            "nothing",
+            "other 2.5",
+            "other [1, 2, 3]",
+            "wildcard",
+            "6",
+        ],
+    );
+    let output = run(r#"
+match 1 {
+  _ => print "first";
+  2 => print "never";
+}
+match 2 {
+  1 | 2 => print "a";
+  2 => print "b";
+}
+"#);
+    assert_eq!(output.status, 0, "stderr: {}", output.stderr);
+    assert_eq!(output.stdout, "first\na\n");
+    assert!(
+        output
+            .stderr
+            .contains("[line 4] Warning at '2': Unreachable match arm."),
+        "stderr: {}",
+        output.stderr
+    );
+    assert!(
+        output
+            .stderr
+            .contains("[line 8] Warning at '2': Unreachable match arm."),
+        "stderr: {}",
+        output.stderr
+    );
+}
+
+#[test]
+fn destructuring_declarations() {
+    assert_prints(
+        r#"
+var [a, b] = [1, 2];
+print a + b;
+class Point { init(x, y) { this.x = x; this.y = y; } }
+var {x, y} = Point(3, 4);
+print x * y;
+fun f() {
+  var [first, second] = ["l", "r"];
+  var {x} = Point(9, 0);
+  return first + second + "${x}";
+}
+print f();
+fun sum([p, q], {y}) { return p + q + y; }
+print sum([1, 2], Point(0, 10));
+"#,
+        &["3", "12", "lr9", "13"],
+    );
+    assert_runtime_error(
+        "var [a, b] = [1];\n",
+        "Expected a list of 2 elements but got 1.",
+    );
+    assert_runtime_error(
+        "var [a, b] = 5;\n",
+        "Only lists can be destructured with '[...]'.",
+    );
+    assert_runtime_error("class P {}\nvar {x} = P();\n", "Undefined property 'x'.");
+}
+
+#[test]
+fn default_rest_and_named_parameters() {
+    assert_prints(
+        r#"
+fun greet(name, greeting = "Hello") { return greeting + " " + name; }
+print greet("Ada");
+print greet("Ada", "Hi");
+var calls = 0;
+fun stamp() { calls += 1; return calls; }
+fun at(t = stamp()) { return t; }
+print at();
+print at();
+print at(10);
+fun collect(first, ...rest) { return [first, rest]; }
+print collect(1);
+print collect(1, 2, 3);
+fun three(a, b, c) { return a + b + c; }
+var args = [1, 2, 3];
+print three(...args);
+print three(1, ...[2, 3]);
+fun box(width = 1, height = 2) { return "${width}x${height}"; }
+print box(height: 5);
+print box(height: 3, width: 4);
+"#,
+        &[
+            "Hello Ada",
+            "Hi Ada",
+            "1",
+            "2",
+            "10",
+            "[1, []]",
+            "[1, [2, 3]]",
+            "6",
+            "6",
+            "1x5",
+            "4x3",
+        ],
+    );
+    assert_runtime_error(
+        "fun f(a, b = 1) {}\nf();\n",
+        "Expected 1 to 2 arguments but got 0.",
+    );
+    assert_runtime_error(
+        "fun f(a, b) {}\nf(...[1]);\n",
+        "Expected 2 arguments but got 1.",
+    );
+    assert_runtime_error("fun f(a) {}\nf(b: 1);\n", "Unexpected argument 'b'.");
+    assert_compile_error("fun f(...rest, a) {}\n", "Rest parameter must be last.");
+}
+
+#[test]
+fn constants_cannot_be_reassigned() {
+    assert_prints(
+        r#"
+const limit = 10;
+print limit;
+{
+  const local = "fixed";
+  print local;
+}
+fun f() { const inner = 1; return () => inner; }
+print f()();
+"#,
+        &["10", "fixed", "1"],
+    );
+    assert_compile_error("const a = 1;\na = 2;\n", "Can't assign to constant 'a'.");
+    assert_compile_error("const a;\n", "Expect '=' after constant name.");
+    assert_compile_error(
+        "{ const a = 1; a += 1; }\n",
+        "Can't assign to constant 'a'.",
+    );
+    assert_compile_error(
+        "fun f() { const a = 1; return () => { a = 2; }; }\n",
+        "Can't assign to constant 'a'.",
+    );
+    let output = run_modules(&[
+        ("main.lox", "import \"lib.lox\" as lib;\nlib.limit = 5;\n"),
+        ("lib.lox", "const limit = 1;\n"),
+    ]);
+    assert_eq!(output.status, 70);
+    assert!(
+        output.stderr.contains("Can't assign to constant 'limit'."),
+        "stderr: {}",
+        output.stderr
+    );
+}
+
+#[test]
+fn classes_with_static_methods_and_accessors() {
+    assert_prints(
+        r#"
+class Rect {
+  init(w, h) { this.w = w; this.h = h; }
+  get area { return this.w * this.h; }
+  set width(v) { this.w = v; }
+  static square(n) { return Rect(n, n); }
+  describe() { return "${this.w}x${this.h}"; }
+}
+var r = Rect(2, 3);
+print r.area;
+r.width = 5;
+print r.area;
+print (r.width = 7);
+print r.describe();
+print Rect.square(4).area;
+var m = r.describe;
+print m();
+print Rect;
+print r;
+"#,
+        &[
+            "6",
+            "15",
+            "7",
+            "7x3",
+            "16",
+            "7x3",
+            "<class Rect>",
+            "<Rect instance>",
+        ],
+    );
+    assert_runtime_error(
+        "class A { get x { return 1; } }\nA().x = 2;\n",
+        "Property 'x' has a getter but no setter.",
+    );
+    assert_runtime_error(
+        "class A { static s() { return 1; } }\nA().s();\n",
+        "Undefined property 's'.",
+    );
+    assert_runtime_error("class A {}\nA(1);\n", "Expected 0 arguments but got 1.");
+    assert_compile_error(
+        "class A { set x(a, b) {} }\n",
+        "A setter must have exactly one parameter.",
+    );
+    assert_compile_error(
+        "class A { init() { return 1; } }\n",
+        "Can't return a value from an initializer.",
+    );
+    assert_compile_error("print this;\n", "Can't use 'this' outside of a class.");
+}
+
+#[test]
+fn operators_dispatch_to_special_methods() {
+    assert_prints(
+        r#"
+class Vec {
+  init(x, y) { this.x = x; this.y = y; }
+  __add__(o) => Vec(this.x + o.x, this.y + o.y)
+  __sub__(o) => Vec(this.x - o.x, this.y - o.y)
+  __mul__(k) => Vec(this.x * k, this.y * k)
+  __neg__() => Vec(-this.x, -this.y)
+  __eq__(o) => this.x == o.x and this.y == o.y
+  __lt__(o) => this.x < o.x
+  __str__() => "Vec(${this.x}, ${this.y})"
+}
+var a = Vec(1, 2);
+var b = Vec(3, 4);
+print a + b;
+print b - a;
+print a * 3;
+print -a;
+print a == Vec(1, 2);
+print a != b;
+print a < b;
+print "a is ${a}";
+class Plain {}
+var p = Plain();
+print p == p;
+print p == Plain();
+"#,
+        &[
+            "Vec(4, 6)",
+            "Vec(2, 2)",
+            "Vec(3, 6)",
+            "Vec(-1, -2)",
+            "true",
+            "true",
+            "true",
+            "a is Vec(1, 2)",
+            "true",
+            "false",
+        ],
+    );
+    assert_runtime_error(
+        "class Plain {}\nprint Plain() + 1;\n",
+        "'Plain' has no '__add__' method.",
+    );
+    assert_runtime_error(
+        "class Plain {}\nprint -Plain();\n",
+        "'Plain' has no '__neg__' method.",
+    );
+    assert_runtime_error(
+        "class V { __lt__(o) => true }\nprint V() > V();\n",
+        "'V' has no '__gt__' method.",
+    );
+    assert_runtime_error(
+        "class S { __str__() => 1 }\nprint S();\n",
+        "'__str__' must return a string.",
+    );
+}